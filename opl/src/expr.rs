@@ -0,0 +1,503 @@
+//! Small expression language for `[datamappings]`, e.g. `Ignition = elec_busbar_main > 0 && batterie_main > 0`.
+//!
+//! All values are `f32`, comparisons and logical operators return `1.0` or `0.0`.
+
+// nesting of parentheses, function calls and unary operators, deeper input would overflow the
+// stack of OMSI
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Func {
+    Abs,
+    Min,
+    Max,
+    Round,
+    Floor,
+    Ceil,
+}
+
+impl Func {
+    fn from_name(name: &str) -> Option<(Func, usize)> {
+        match name {
+            "abs" => Some((Func::Abs, 1)),
+            "min" => Some((Func::Min, 2)),
+            "max" => Some((Func::Max, 2)),
+            "round" => Some((Func::Round, 1)),
+            "floor" => Some((Func::Floor, 1)),
+            "ceil" => Some((Func::Ceil, 1)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    Num(f32),
+    /// index into the combined system and user variable list
+    Var(usize),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+fn truth(v: f32) -> bool {
+    v != 0.0
+}
+
+fn from_bool(b: bool) -> f32 {
+    if b { 1.0 } else { 0.0 }
+}
+
+impl Expr {
+    /// Parses an expression, `resolve` maps a variable name to its index.
+    pub fn parse(src: &str, resolve: &dyn Fn(&str) -> Option<usize>) -> Result<Expr, String> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+            resolve,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(t) = parser.peek() {
+            return Err(format!("unexpected '{}'", t));
+        }
+        Ok(expr)
+    }
+
+    /// Evaluates the expression, `value` returns the current value of a variable index.
+    pub fn eval(&self, value: &dyn Fn(usize) -> f32) -> f32 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Var(idx) => value(*idx),
+            Expr::Neg(e) => -e.eval(value),
+            Expr::Not(e) => from_bool(!truth(e.eval(value))),
+            Expr::Binary(op, l, r) => {
+                let a = l.eval(value);
+                // short circuit like the C operators do
+                match op {
+                    BinOp::Or if truth(a) => return 1.0,
+                    BinOp::And if !truth(a) => return 0.0,
+                    _ => {}
+                }
+                let b = r.eval(value);
+                match op {
+                    BinOp::Or | BinOp::And => from_bool(truth(b)),
+                    BinOp::Eq => from_bool(a == b),
+                    BinOp::Ne => from_bool(a != b),
+                    BinOp::Lt => from_bool(a < b),
+                    BinOp::Le => from_bool(a <= b),
+                    BinOp::Gt => from_bool(a > b),
+                    BinOp::Ge => from_bool(a >= b),
+                    BinOp::Add => a + b,
+                    BinOp::Sub => a - b,
+                    BinOp::Mul => a * b,
+                    // a missing capacity or similar must not send NaN or infinity to the hardware
+                    BinOp::Div => {
+                        if b == 0.0 {
+                            0.0
+                        } else {
+                            a / b
+                        }
+                    }
+                }
+            }
+            Expr::Call(func, args) => {
+                let a = args[0].eval(value);
+                match func {
+                    Func::Abs => a.abs(),
+                    Func::Min => a.min(args[1].eval(value)),
+                    Func::Max => a.max(args[1].eval(value)),
+                    Func::Round => a.round(),
+                    Func::Floor => a.floor(),
+                    Func::Ceil => a.ceil(),
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if `s` is a plain variable name.
+pub fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Num(f32),
+    Ident(String),
+    Op(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Ident(s) => write!(f, "{}", s),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+const OPERATORS: [&str; 17] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "!", "(", ")", ",", "=",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = src.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() || c == '.' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let num = rest[..end]
+                .parse::<f32>()
+                .map_err(|_| format!("invalid number '{}'", &rest[..end]))?;
+            tokens.push(Token::Num(num));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_lowercase()));
+            rest = &rest[end..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            rest = &rest[op.len()..];
+            // a single '=' is accepted as comparison
+            tokens.push(Token::Op(if *op == "=" { "==" } else { op }));
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// `parse_unary` calls on the stack
+    depth: usize,
+    resolve: &'a dyn Fn(&str) -> Option<usize>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(t) => Err(format!("expected '{}', found '{}'", op, t)),
+                None => Err(format!("expected '{}' at end of expression", op)),
+            }
+        }
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinOp)],
+        next: fn(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut left = next(self)?;
+        'outer: loop {
+            for (op, bin_op) in ops {
+                if self.eat(op) {
+                    let right = next(self)?;
+                    left = Expr::Binary(*bin_op, Box::new(left), Box::new(right));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("||", BinOp::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&&", BinOp::And)], Self::parse_comparison)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, String> {
+        self.binary(
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<=", BinOp::Le),
+                (">=", BinOp::Ge),
+                ("<", BinOp::Lt),
+                (">", BinOp::Gt),
+            ],
+            Self::parse_additive,
+        )
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::parse_term)
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, String> {
+        self.binary(&[("*", BinOp::Mul), ("/", BinOp::Div)], Self::parse_unary)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err("expression nested too deeply".to_string());
+        }
+        self.depth += 1;
+        let result = self.parse_unary_inner();
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_unary_inner(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.parse_unary()?)));
+        }
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Ident(name)) => {
+                if self.eat("(") {
+                    let (func, argc) =
+                        Func::from_name(&name).ok_or(format!("unknown function '{}'", name))?;
+                    let mut args = vec![self.parse_or()?];
+                    while self.eat(",") {
+                        args.push(self.parse_or()?);
+                    }
+                    self.expect(")")?;
+                    if args.len() != argc {
+                        return Err(format!(
+                            "function '{}' takes {} argument(s), got {}",
+                            name,
+                            argc,
+                            args.len()
+                        ));
+                    }
+                    Ok(Expr::Call(func, args))
+                } else {
+                    (self.resolve)(&name)
+                        .map(Expr::Var)
+                        .ok_or(format!("unknown variable '{}'", name))
+                }
+            }
+            Some(Token::Op("(")) => {
+                let e = self.parse_or()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Some(t) => Err(format!("unexpected '{}'", t)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 3] = ["door_light_1", "batt_ok", "ai_light"];
+
+    fn eval(src: &str, values: [f32; 3]) -> f32 {
+        let resolve = |name: &str| NAMES.iter().position(|n| *n == name);
+        let expr = Expr::parse(src, &resolve).unwrap();
+        expr.eval(&|idx| values[idx])
+    }
+
+    #[test]
+    fn comparison_as_threshold() {
+        assert_eq!(eval("door_light_1 >= 1", [1.0, 0.0, 0.0]), 1.0);
+        assert_eq!(eval("door_light_1 >= 1", [0.5, 0.0, 0.0]), 0.0);
+        // 0..255 brightness
+        assert_eq!(eval("ai_light > 127", [0.0, 0.0, 200.0]), 1.0);
+        // -1/1 switches
+        assert_eq!(eval("door_light_1 > 0", [-1.0, 0.0, 0.0]), 0.0);
+        // 0/2 lamps
+        assert_eq!(eval("ai_light = 2", [0.0, 0.0, 2.0]), 1.0);
+    }
+
+    #[test]
+    fn inversion() {
+        assert_eq!(eval("!batt_ok", [0.0, 0.0, 0.0]), 1.0);
+        assert_eq!(eval("!batt_ok", [0.0, 1.0, 0.0]), 0.0);
+        assert_eq!(eval("!(ai_light > 127)", [0.0, 0.0, 255.0]), 0.0);
+    }
+
+    #[test]
+    fn precedence_and_short_circuit() {
+        assert_eq!(eval("1 + 2 * 3", [0.0; 3]), 7.0);
+        assert_eq!(eval("-door_light_1 + 1", [3.0, 0.0, 0.0]), -2.0);
+        assert_eq!(eval("batt_ok || door_light_1 / 0", [0.0, 1.0, 0.0]), 1.0);
+        assert_eq!(eval("door_light_1 / 0", [5.0, 0.0, 0.0]), 0.0);
+        assert_eq!(eval("max(door_light_1, ai_light)", [3.0, 0.0, 4.0]), 4.0);
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(eval("batt_ok && ai_light", [0.0, 1.0, 2.0]), 1.0);
+        assert_eq!(eval("batt_ok && ai_light", [0.0, 1.0, 0.0]), 0.0);
+        assert_eq!(eval("batt_ok || ai_light", [0.0, 0.0, 0.0]), 0.0);
+        assert_eq!(eval("door_light_1 != 1", [2.0, 0.0, 0.0]), 1.0);
+        assert_eq!(eval("door_light_1 == 1", [2.0, 0.0, 0.0]), 0.0);
+        assert_eq!(eval("door_light_1 < 1", [0.5, 0.0, 0.0]), 1.0);
+        assert_eq!(eval("door_light_1 <= 1", [1.0, 0.0, 0.0]), 1.0);
+        assert_eq!(eval("10 - 4 - 3", [0.0; 3]), 3.0);
+        assert_eq!(eval("12 / 3 / 2", [0.0; 3]), 2.0);
+        assert_eq!(eval("(1 + 2) * 3", [0.0; 3]), 9.0);
+        assert_eq!(eval("!!ai_light", [0.0, 0.0, 5.0]), 1.0);
+        assert_eq!(eval("--door_light_1", [4.0, 0.0, 0.0]), 4.0);
+        // comparison binds weaker than arithmetic, && weaker than comparison
+        assert_eq!(eval("ai_light > 1 + 2 && batt_ok", [0.0, 1.0, 4.0]), 1.0);
+        assert_eq!(eval("0.25 * 4", [0.0; 3]), 1.0);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("abs(door_light_1)", [-3.0, 0.0, 0.0]), 3.0);
+        assert_eq!(eval("min(door_light_1, ai_light)", [3.0, 0.0, 4.0]), 3.0);
+        assert_eq!(eval("round(door_light_1)", [2.5, 0.0, 0.0]), 3.0);
+        assert_eq!(eval("floor(door_light_1)", [2.9, 0.0, 0.0]), 2.0);
+        assert_eq!(eval("ceil(door_light_1)", [2.1, 0.0, 0.0]), 3.0);
+        assert_eq!(
+            eval("max(min(ai_light, 100), 0) / 100", [0.0, 0.0, 250.0]),
+            1.0
+        );
+        assert_eq!(eval("ABS(-2)", [0.0; 3]), 2.0);
+    }
+
+    #[test]
+    fn more_errors() {
+        let resolve = |name: &str| NAMES.iter().position(|n| *n == name);
+        assert_eq!(
+            Expr::parse("sqrt(batt_ok)", &resolve),
+            Err("unknown function 'sqrt'".to_string())
+        );
+        assert_eq!(
+            Expr::parse("1.2.3", &resolve),
+            Err("invalid number '1.2.3'".to_string())
+        );
+        assert_eq!(
+            Expr::parse("batt_ok 1", &resolve),
+            Err("unexpected '1'".to_string())
+        );
+        assert_eq!(
+            Expr::parse("(batt_ok", &resolve),
+            Err("expected ')' at end of expression".to_string())
+        );
+        assert_eq!(
+            Expr::parse("abs(batt_ok, 1 2)", &resolve),
+            Err("expected ')', found '2'".to_string())
+        );
+        assert_eq!(
+            Expr::parse("", &resolve),
+            Err("unexpected end of expression".to_string())
+        );
+        let too_deep = Err("expression nested too deeply".to_string());
+        assert_eq!(Expr::parse(&"-".repeat(10_000), &resolve), too_deep);
+        assert_eq!(
+            Expr::parse(
+                &format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000)),
+                &resolve
+            ),
+            too_deep
+        );
+        assert_eq!(Expr::parse(&"abs(".repeat(100), &resolve), too_deep);
+        let nested = format!(
+            "{}batt_ok{}",
+            "(".repeat(MAX_DEPTH - 1),
+            ")".repeat(MAX_DEPTH - 1)
+        );
+        assert!(Expr::parse(&nested, &resolve).is_ok());
+        assert_eq!(
+            Expr::parse(")", &resolve),
+            Err("unexpected ')'".to_string())
+        );
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        assert_eq!(eval("Batt_OK", [0.0, 1.0, 0.0]), 1.0);
+    }
+
+    #[test]
+    fn errors() {
+        let resolve = |name: &str| NAMES.iter().position(|n| *n == name);
+        assert_eq!(
+            Expr::parse("tempo > 1", &resolve),
+            Err("unknown variable 'tempo'".to_string())
+        );
+        assert_eq!(
+            Expr::parse("abs(batt_ok", &resolve),
+            Err("expected ')' at end of expression".to_string())
+        );
+        assert_eq!(
+            Expr::parse("min(batt_ok)", &resolve),
+            Err("function 'min' takes 2 argument(s), got 1".to_string())
+        );
+        assert_eq!(
+            Expr::parse("batt_ok $ 1", &resolve),
+            Err("unexpected character '$'".to_string())
+        );
+        assert!(Expr::parse("batt_ok >", &resolve).is_err());
+    }
+
+    #[test]
+    fn identifiers() {
+        assert!(is_identifier("ai_blinker_l"));
+        assert!(is_identifier("_x1"));
+        assert!(!is_identifier("1x"));
+        assert!(!is_identifier("a b"));
+        assert!(!is_identifier(""));
+    }
+}
@@ -0,0 +1,2108 @@
+//! Parser for the OMSI 2 plugin files (`.opl`) of omsi2komsi and omsilogger.
+//!
+//! The parser has no Windows dependencies, so it can be tested on any host, e.g. with
+//! `cargo test -p omsi_opl --target x86_64-unknown-linux-gnu`.
+//! Problems in the file never abort parsing, they are collected as line-numbered [`Diagnostic`]s.
+
+use std::fmt;
+use std::path::Path;
+
+pub mod expr;
+
+use expr::Expr;
+
+/// F10
+pub const DEFAULT_HOTKEY: u32 = 0x79;
+
+/// Targets in `[inputmappings]` and keys in `[triggerhotkeys]` that reset the trip counters.
+pub const TRIP_RESETS: [&str; 2] = ["tripreset1", "tripreset2"];
+
+/// Target in `[inputmappings]` and key in `[triggerhotkeys]` that resends the complete state.
+pub const RESYNC: &str = "resync";
+
+/// A problem found while parsing, `line` starts at 1.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// A `key = value` line, key and value are trimmed but keep their case.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+/// A list as OMSI reads it: a line with the number of names followed by the names.
+///
+/// OMSI hands the names to the plugin by their position in the list, names beyond the count are not part of it.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct NameList {
+    /// line of the section header
+    pub line: usize,
+    pub count: Option<usize>,
+    pub names: Vec<String>,
+    /// line of each name
+    pub lines: Vec<usize>,
+}
+
+impl NameList {
+    /// Returns the index of `name`, OMSI variable names are not case sensitive.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    fn push(
+        &mut self,
+        line: &str,
+        line_no: usize,
+        section: &str,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        if self.count.is_none() && self.names.is_empty() {
+            if let Ok(count) = line.parse::<usize>() {
+                self.count = Some(count);
+                return;
+            }
+            diagnostics.push(Diagnostic {
+                line: line_no,
+                message: format!("[{}] should start with the number of entries", section),
+            });
+        }
+
+        if let Some(count) = self.count
+            && self.names.len() >= count
+        {
+            diagnostics.push(Diagnostic {
+                line: line_no,
+                message: format!(
+                    "'{}' exceeds the count of {} in [{}] and is ignored by OMSI",
+                    line, count, section
+                ),
+            });
+            return;
+        }
+
+        self.names.push(line.to_string());
+        self.lines.push(line_no);
+    }
+}
+
+/// A `[profile:name]` section, the settings of a profile override the ones from `[omsi2komsi]`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Profile {
+    pub name: String,
+    pub line: usize,
+    /// `stringvar`, the string variable whose value selects the profile
+    pub string_var: Option<Entry>,
+    /// `match`, `*` is a wildcard
+    pub pattern: String,
+    pub engine_on_value: Option<u8>,
+    pub threshold: Option<f32>,
+    pub engine_mode: Option<EngineMode>,
+    pub engine_rpm: Option<f32>,
+    /// all other lines, they work like `[datamappings]`
+    pub mappings: Vec<Entry>,
+}
+
+impl Profile {
+    fn push(&mut self, entry: Entry, diagnostics: &mut Vec<Diagnostic>) {
+        let ok = match entry.key.to_lowercase().as_str() {
+            "stringvar" => {
+                self.string_var = Some(entry);
+                return;
+            }
+            "match" => {
+                self.pattern = entry.value;
+                return;
+            }
+            "engineonvalue" => set(
+                &mut self.engine_on_value,
+                entry.value.parse().ok().map(Some),
+            ),
+            "threshold" => set(&mut self.threshold, entry.value.parse().ok().map(Some)),
+            "enginemode" => set(
+                &mut self.engine_mode,
+                EngineMode::from_name(&entry.value).map(Some),
+            ),
+            "enginerpm" => set(&mut self.engine_rpm, entry.value.parse().ok().map(Some)),
+            _ => {
+                self.mappings.push(entry);
+                return;
+            }
+        };
+        if !ok {
+            diagnostics.push(Diagnostic {
+                line: entry.line,
+                message: format!("invalid value '{}' for '{}'", entry.value, entry.key),
+            });
+        }
+    }
+}
+
+/// How the indicators are sent with the KOMSI `D` command, `indicatormode` in `[omsi2komsi]`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IndicatorMode {
+    /// `1` while exactly one side blinks, for firmware with a single indicator lamp
+    Combined,
+    /// `1` left, `2` right and `3` both sides
+    Separate,
+}
+
+impl IndicatorMode {
+    fn from_name(s: &str) -> Option<IndicatorMode> {
+        match s.to_lowercase().as_str() {
+            "combined" => Some(IndicatorMode::Combined),
+            "separate" => Some(IndicatorMode::Separate),
+            _ => None,
+        }
+    }
+}
+
+/// Where the engine state comes from, `enginemode` in `[omsi2komsi]` or a profile.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EngineMode {
+    /// the battery light state compared with `engineonvalue`
+    Battery,
+    /// the mapped `Engine` variable compared with `engineonvalue`
+    Engine,
+    /// the mapped `Rpm` variable above `enginerpm`
+    Rpm,
+}
+
+impl EngineMode {
+    fn from_name(s: &str) -> Option<EngineMode> {
+        match s.to_lowercase().as_str() {
+            "battery" => Some(EngineMode::Battery),
+            "engine" => Some(EngineMode::Engine),
+            "rpm" => Some(EngineMode::Rpm),
+            _ => None,
+        }
+    }
+}
+
+/// Where the hazard lamp state comes from, `hazardmode` in `[omsi2komsi]`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HazardMode {
+    /// both indicators on at the same time
+    Indicators,
+    /// the mapped `WarningLights` switch
+    Switch,
+    /// either of them
+    Both,
+}
+
+impl HazardMode {
+    fn from_name(s: &str) -> Option<HazardMode> {
+        match s.to_lowercase().as_str() {
+            "indicators" => Some(HazardMode::Indicators),
+            "switch" => Some(HazardMode::Switch),
+            "both" => Some(HazardMode::Both),
+            _ => None,
+        }
+    }
+}
+
+/// Unit of the speed sent to the hardware, `speedunit` in `[omsi2komsi]`. OMSI itself uses km/h.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SpeedUnit {
+    Kmh,
+    Mph,
+    /// metres per second
+    Ms,
+}
+
+impl SpeedUnit {
+    fn from_name(s: &str) -> Option<SpeedUnit> {
+        match s.to_lowercase().as_str() {
+            "kmh" | "km/h" => Some(SpeedUnit::Kmh),
+            "mph" => Some(SpeedUnit::Mph),
+            "ms" | "m/s" => Some(SpeedUnit::Ms),
+            _ => None,
+        }
+    }
+
+    /// Converts a speed in km/h to this unit.
+    pub fn from_kmh(self, kmh: f32) -> f32 {
+        match self {
+            SpeedUnit::Kmh => kmh,
+            SpeedUnit::Mph => kmh / 1.609_344,
+            SpeedUnit::Ms => kmh / 3.6,
+        }
+    }
+}
+
+/// How the speed is rounded to whole numbers, `speedrounding` in `[omsi2komsi]`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rounding {
+    Floor,
+    Round,
+    Ceil,
+}
+
+impl Rounding {
+    fn from_name(s: &str) -> Option<Rounding> {
+        match s.to_lowercase().as_str() {
+            "floor" => Some(Rounding::Floor),
+            "round" => Some(Rounding::Round),
+            "ceil" => Some(Rounding::Ceil),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, v: f32) -> f32 {
+        match self {
+            Rounding::Floor => v.floor(),
+            Rounding::Round => v.round(),
+            Rounding::Ceil => v.ceil(),
+        }
+    }
+}
+
+/// Character set of the texts from `[stringmappings]`, `stringencoding` in `[omsi2komsi]`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TextEncoding {
+    /// umlauts and accents transliterated, e.g. `ä` as `ae`, other characters as `?`
+    Ascii,
+    /// ISO 8859-1, one byte per character, characters outside of it transliterated like `Ascii`
+    Latin1,
+    Utf8,
+}
+
+impl TextEncoding {
+    fn from_name(s: &str) -> Option<TextEncoding> {
+        match s.to_lowercase().as_str() {
+            "ascii" => Some(TextEncoding::Ascii),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(TextEncoding::Latin1),
+            "utf8" | "utf-8" => Some(TextEncoding::Utf8),
+            _ => None,
+        }
+    }
+
+    /// Encodes a text for a KOMSI text command, cut to `max_len` characters (0 = no limit).
+    ///
+    /// Double quotes end a text command, so they become `'`. Line breaks and other control
+    /// characters become spaces, leading and trailing spaces are removed.
+    pub fn encode(self, text: &str, max_len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut len = 0;
+        for c in text.trim().chars() {
+            let c = match c {
+                '"' | '„' | '“' | '”' => '\'',
+                c if c.is_control() => ' ',
+                c => c,
+            };
+            let mut utf8 = [0u8; 4];
+            let (bytes, chars): (&[u8], usize) = match self {
+                TextEncoding::Utf8 => (c.encode_utf8(&mut utf8).as_bytes(), 1),
+                TextEncoding::Latin1 if (c as u32) < 0x100 => {
+                    utf8[0] = c as u8;
+                    (&utf8[..1], 1)
+                }
+                _ if c.is_ascii() => {
+                    utf8[0] = c as u8;
+                    (&utf8[..1], 1)
+                }
+                _ => {
+                    let t = transliterate(c);
+                    (t.as_bytes(), t.len())
+                }
+            };
+            if max_len > 0 && len + chars > max_len {
+                break;
+            }
+            out.extend_from_slice(bytes);
+            len += chars;
+        }
+        out
+    }
+}
+
+/// ASCII replacement for the characters of German and other Latin script texts.
+fn transliterate(c: char) -> &'static str {
+    match c {
+        'ä' => "ae",
+        'ö' => "oe",
+        'ü' => "ue",
+        'Ä' => "Ae",
+        'Ö' => "Oe",
+        'Ü' => "Ue",
+        'ß' => "ss",
+        'à' | 'á' | 'â' | 'ã' | 'å' => "a",
+        'À' | 'Á' | 'Â' | 'Ã' | 'Å' => "A",
+        'ç' | 'č' | 'ć' => "c",
+        'Ç' | 'Č' | 'Ć' => "C",
+        'è' | 'é' | 'ê' | 'ë' | 'ě' | 'ę' => "e",
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ě' | 'Ę' => "E",
+        'ì' | 'í' | 'î' | 'ï' => "i",
+        'Ì' | 'Í' | 'Î' | 'Ï' => "I",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ø' => "o",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ø' => "O",
+        'ù' | 'ú' | 'û' | 'ů' => "u",
+        'Ù' | 'Ú' | 'Û' | 'Ů' => "U",
+        'ý' | 'ÿ' => "y",
+        'Ý' => "Y",
+        'ł' => "l",
+        'Ł' => "L",
+        'ř' => "r",
+        'Ř' => "R",
+        'š' | 'ś' => "s",
+        'Š' | 'Ś' => "S",
+        'ž' | 'ź' | 'ż' => "z",
+        'Ž' | 'Ź' | 'Ż' => "Z",
+        '–' | '—' => "-",
+        '‘' | '’' | '‚' | '´' => "'",
+        '\u{a0}' => " ",
+        _ => "?",
+    }
+}
+
+/// A line of `[stringmappings]`: a `[stringvarlist]` variable sent as text with a KOMSI letter.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StringMapping {
+    pub letter: char,
+    pub string_var: String,
+    /// maximum length in characters, `None` uses `stringmaxlength`
+    pub max_len: Option<usize>,
+    pub line: usize,
+}
+
+impl StringMapping {
+    /// Parses `letter = stringvar[, maxlength]`.
+    fn parse(entry: &Entry) -> Option<StringMapping> {
+        let letter = parse_command_letter(&entry.key)??;
+        let (string_var, max_len) = match entry.value.split_once(',') {
+            Some((var, len)) => (var.trim(), Some(len.trim().parse().ok()?)),
+            None => (entry.value.as_str(), None),
+        };
+        if string_var.is_empty() {
+            return None;
+        }
+        Some(StringMapping {
+            letter,
+            string_var: string_var.to_string(),
+            max_len,
+            line: entry.line,
+        })
+    }
+}
+
+/// How a serial port is found, from the value of `portname`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum PortSelector {
+    /// a fixed name like `com8`
+    Name(String),
+    /// `usb:VID:PID` or `usb:VID:PID:SERIAL`, the IDs in hex
+    Usb {
+        vid: u16,
+        pid: u16,
+        serial: Option<String>,
+    },
+    /// `serial:NUMBER`, the USB serial number of the device
+    SerialNumber(String),
+    /// `product:TEXT`, part of the USB product string
+    Product(String),
+}
+
+impl PortSelector {
+    pub fn parse(s: &str) -> Option<PortSelector> {
+        let Some((kind, rest)) = s.split_once(':') else {
+            return Some(PortSelector::Name(s.to_string()));
+        };
+        let rest = rest.trim();
+        match kind.trim().to_lowercase().as_str() {
+            "usb" => {
+                let mut parts = rest.splitn(3, ':');
+                let vid = u16::from_str_radix(parts.next()?.trim(), 16).ok()?;
+                let pid = u16::from_str_radix(parts.next()?.trim(), 16).ok()?;
+                let serial = parts.next().map(|p| p.trim().to_string());
+                Some(PortSelector::Usb { vid, pid, serial })
+            }
+            "serial" if !rest.is_empty() => Some(PortSelector::SerialNumber(rest.to_string())),
+            "product" if !rest.is_empty() => Some(PortSelector::Product(rest.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Tells whether a USB device matches, text is compared without case.
+    ///
+    /// A [`PortSelector::Name`] never matches, it is opened directly.
+    pub fn matches_usb(
+        &self,
+        vid: u16,
+        pid: u16,
+        serial: Option<&str>,
+        product: Option<&str>,
+    ) -> bool {
+        let same = |a: &str, b: Option<&str>| b.is_some_and(|b| a.eq_ignore_ascii_case(b));
+        match self {
+            PortSelector::Name(_) => false,
+            PortSelector::Usb {
+                vid: v,
+                pid: p,
+                serial: s,
+            } => *v == vid && *p == pid && s.as_deref().is_none_or(|s| same(s, serial)),
+            PortSelector::SerialNumber(s) => same(s, serial),
+            PortSelector::Product(text) => {
+                product.is_some_and(|p| p.to_lowercase().contains(&text.to_lowercase()))
+            }
+        }
+    }
+}
+
+/// Parity of a serial port, `parity` in `[port.N]`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+impl Parity {
+    fn from_name(s: &str) -> Option<Parity> {
+        match s.to_lowercase().as_str() {
+            "none" => Some(Parity::None),
+            "odd" => Some(Parity::Odd),
+            "even" => Some(Parity::Even),
+            _ => None,
+        }
+    }
+}
+
+/// Flow control of a serial port, `flowcontrol` in `[port.N]`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FlowControl {
+    None,
+    /// XON/XOFF
+    Software,
+    /// RTS/CTS
+    Hardware,
+}
+
+impl FlowControl {
+    fn from_name(s: &str) -> Option<FlowControl> {
+        match s.to_lowercase().as_str() {
+            "none" => Some(FlowControl::None),
+            "software" => Some(FlowControl::Software),
+            "hardware" => Some(FlowControl::Hardware),
+            _ => None,
+        }
+    }
+}
+
+/// A serial port from a `[port.N]` section or from `portname` to `portname5` in `[omsi2komsi]`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PortSettings {
+    /// `N` of `[port.N]`, or the key `portname` to `portname5`
+    pub name: String,
+    pub line: usize,
+    /// a COM name or a USB device, see [`PortSelector`]
+    pub portname: String,
+    pub baudrate: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+    pub flow_control: FlowControl,
+    /// `timeout` in milliseconds for reads and writes
+    pub timeout_ms: u64,
+    /// `fields`, the comma separated KOMSI fields the port receives, all fields without it
+    pub fields: Option<Entry>,
+}
+
+impl PortSettings {
+    fn new(name: &str, line: usize, portname: &str, baudrate: u32) -> Self {
+        PortSettings {
+            name: name.to_string(),
+            line,
+            portname: portname.to_string(),
+            baudrate,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+            timeout_ms: 10,
+            fields: None,
+        }
+    }
+
+    /// The names in `fields`, `None` when the port receives all fields.
+    pub fn field_names(&self) -> Option<Vec<&str>> {
+        self.fields.as_ref().map(|entry| {
+            entry
+                .value
+                .split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .collect()
+        })
+    }
+
+    fn push(&mut self, entry: Entry, diagnostics: &mut Vec<Diagnostic>) {
+        let value = entry.value.as_str();
+        let ok = match entry.key.to_lowercase().as_str() {
+            "portname" => {
+                self.portname = value.to_string();
+                PortSelector::parse(value).is_some() && !value.is_empty()
+            }
+            "baudrate" => set(&mut self.baudrate, value.parse().ok()),
+            "databits" => set(
+                &mut self.data_bits,
+                value.parse().ok().filter(|b| (5..=8).contains(b)),
+            ),
+            "parity" => set(&mut self.parity, Parity::from_name(value)),
+            "stopbits" => set(
+                &mut self.stop_bits,
+                value.parse().ok().filter(|b| *b == 1 || *b == 2),
+            ),
+            "flowcontrol" => set(&mut self.flow_control, FlowControl::from_name(value)),
+            "timeout" => set(&mut self.timeout_ms, value.parse().ok()),
+            "fields" => {
+                self.fields = Some(entry);
+                return;
+            }
+            _ => {
+                diagnostics.push(Diagnostic {
+                    line: entry.line,
+                    message: format!("unknown setting '{}' in [port.{}]", entry.key, self.name),
+                });
+                return;
+            }
+        };
+        if !ok {
+            diagnostics.push(Diagnostic {
+                line: entry.line,
+                message: format!("invalid value '{}' for '{}'", entry.value, entry.key),
+            });
+        }
+    }
+}
+
+/// The settings from `[omsi2komsi]`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Settings {
+    /// `portname` and `portname2` to `portname5`, empty values are left out, see [`PortSelector`]
+    pub portnames: Vec<String>,
+    pub baudrate: u32,
+    pub serial_port_enabled: bool,
+    pub debug: bool,
+    pub engine_on_value: u8,
+    /// values above the threshold switch lamps and states on
+    pub threshold: f32,
+    pub indicator_mode: IndicatorMode,
+    pub engine_mode: EngineMode,
+    pub engine_rpm: f32,
+    pub hazard_mode: HazardMode,
+    /// milliseconds the hazard lamp stays on after the last on phase, 0 sends the raw state
+    pub hazard_latch_ms: u64,
+    /// milliseconds an indicator counts as active after its last on phase, 0 sends the raw blink phase
+    pub blink_hold_ms: u64,
+    /// `trip1command` and `trip2command`, the KOMSI letters the trip counters are sent with
+    pub trip_commands: [Option<char>; 2],
+    pub speed_unit: SpeedUnit,
+    /// factor for speedometers that are calibrated differently, applied after the unit conversion
+    pub speed_scale: f32,
+    pub speed_rounding: Rounding,
+    /// `reversecommand`, the KOMSI letter the reverse flag is sent with
+    pub reverse_command: Option<char>,
+    /// seconds between two complete resends of the state, 0 sends only changes
+    pub resync_interval_s: u64,
+    /// `udptargets`, comma separated `host:port` addresses the KOMSI lines are sent to as UDP datagrams
+    pub udp_targets: Vec<String>,
+    /// `tcplisten`, the `address:port` of the TCP server for KOMSI clients, `None` without server
+    pub tcp_listen: Option<String>,
+    /// `weblisten`, the `address:port` of the HTTP and WebSocket server, `None` without server
+    pub web_listen: Option<String>,
+    /// `webroot`, the directory the HTTP server serves, relative to omsi2komsi.opl
+    pub web_root: String,
+    /// `mqttbroker`, the `host:port` of the MQTT broker, `None` without MQTT
+    pub mqtt_broker: Option<String>,
+    /// `mqtttopic`, the prefix of the state topics and the `set` topics for inputs
+    pub mqtt_topic: String,
+    pub mqtt_client_id: String,
+    pub mqtt_user: Option<String>,
+    pub mqtt_password: Option<String>,
+    /// `stringencoding`, the character set of the `[stringmappings]` texts
+    pub text_encoding: TextEncoding,
+    /// `stringmaxlength`, the default maximum length of the texts, 0 = no limit
+    pub text_max_len: usize,
+    /// `record`, writes the variable values OMSI hands over to a file for `omsi2komsi-replay`
+    pub record: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            portnames: vec!["com1".to_string()],
+            baudrate: 115200,
+            serial_port_enabled: false,
+            debug: false,
+            engine_on_value: 1,
+            threshold: 0.5,
+            indicator_mode: IndicatorMode::Combined,
+            engine_mode: EngineMode::Battery,
+            engine_rpm: 300.0,
+            hazard_mode: HazardMode::Indicators,
+            hazard_latch_ms: 0,
+            blink_hold_ms: 0,
+            trip_commands: [None; 2],
+            speed_unit: SpeedUnit::Kmh,
+            speed_scale: 1.0,
+            speed_rounding: Rounding::Floor,
+            reverse_command: None,
+            resync_interval_s: 0,
+            udp_targets: Vec::new(),
+            tcp_listen: None,
+            web_listen: None,
+            web_root: "omsi2komsi_web".to_string(),
+            mqtt_broker: None,
+            mqtt_topic: "omsi/bus".to_string(),
+            mqtt_client_id: "omsi2komsi".to_string(),
+            mqtt_user: None,
+            mqtt_password: None,
+            text_encoding: TextEncoding::Ascii,
+            text_max_len: 0,
+            record: false,
+        }
+    }
+}
+
+/// The parsed plugin file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Opl {
+    pub dll: Vec<String>,
+    pub varlist: NameList,
+    pub systemvarlist: NameList,
+    pub stringvarlist: NameList,
+    pub triggers: NameList,
+    pub datamappings: Vec<Entry>,
+    pub inputmappings: Vec<Entry>,
+    pub triggerhotkeys: Vec<Entry>,
+    /// `[mqtttopics]`, field or string variable = topic, replacing the topic below `mqtttopic`
+    pub mqtttopics: Vec<Entry>,
+    pub stringmappings: Vec<StringMapping>,
+    pub profiles: Vec<Profile>,
+    /// the `[port.N]` sections, in the order of the file
+    pub ports: Vec<PortSettings>,
+    pub hotkey: u32,
+    pub settings: Settings,
+    /// `[omsi2komsi]` as written, for the line numbers of the settings
+    pub omsi2komsi: Vec<Entry>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Default for Opl {
+    fn default() -> Self {
+        Opl {
+            dll: Vec::new(),
+            varlist: NameList::default(),
+            systemvarlist: NameList::default(),
+            stringvarlist: NameList::default(),
+            triggers: NameList::default(),
+            datamappings: Vec::new(),
+            inputmappings: Vec::new(),
+            triggerhotkeys: Vec::new(),
+            mqtttopics: Vec::new(),
+            stringmappings: Vec::new(),
+            profiles: Vec::new(),
+            ports: Vec::new(),
+            hotkey: DEFAULT_HOTKEY,
+            settings: Settings::default(),
+            omsi2komsi: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Current {
+    Outside,
+    Dll,
+    VarList,
+    SystemVarList,
+    StringVarList,
+    Triggers,
+    DataMappings,
+    InputMappings,
+    TriggerHotkeys,
+    MqttTopics,
+    StringMappings,
+    Profile,
+    /// index into the collected `[port.N]` sections
+    Port(usize),
+    Hotkey,
+    Omsi2Komsi,
+    Ignored,
+}
+
+impl Opl {
+    /// Reads and parses a plugin file, invalid UTF-8 (e.g. umlauts saved as Windows-1252) is replaced.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Opl> {
+        let bytes = std::fs::read(path)?;
+        Ok(Opl::parse(&String::from_utf8_lossy(&bytes)))
+    }
+
+    pub fn parse(content: &str) -> Opl {
+        let mut opl = Opl::default();
+        let mut current = Current::Outside;
+        let mut seen: Vec<(String, usize)> = Vec::new();
+        let mut settings: Vec<Entry> = Vec::new();
+        // the ports are built after [omsi2komsi], its baudrate is their default
+        let mut port_sections: Vec<(String, usize, Vec<Entry>)> = Vec::new();
+
+        for (i, line) in content.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim_start_matches('\u{feff}').trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) else {
+                    opl.diag(line_no, format!("invalid section header '{}'", line));
+                    current = Current::Ignored;
+                    continue;
+                };
+                let header = header.trim();
+
+                if header.to_lowercase().starts_with("profile:") {
+                    opl.profiles.push(Profile {
+                        name: header["profile:".len()..].trim().to_string(),
+                        line: line_no,
+                        string_var: None,
+                        pattern: "*".to_string(),
+                        engine_on_value: None,
+                        threshold: None,
+                        engine_mode: None,
+                        engine_rpm: None,
+                        mappings: Vec::new(),
+                    });
+                    current = Current::Profile;
+                    continue;
+                }
+
+                if header.to_lowercase().starts_with("port.") {
+                    let name = header["port.".len()..].trim();
+                    match port_sections.iter().position(|(n, _, _)| n == name) {
+                        Some(idx) => {
+                            let first = port_sections[idx].1;
+                            opl.diag(
+                                line_no,
+                                format!(
+                                    "[port.{}] is already defined in line {}, the entries are merged",
+                                    name, first
+                                ),
+                            );
+                            current = Current::Port(idx);
+                        }
+                        None => {
+                            port_sections.push((name.to_string(), line_no, Vec::new()));
+                            current = Current::Port(port_sections.len() - 1);
+                        }
+                    }
+                    continue;
+                }
+
+                let header = header.to_lowercase();
+                current = match header.as_str() {
+                    "dll" => Current::Dll,
+                    "varlist" => Current::VarList,
+                    "systemvarlist" => Current::SystemVarList,
+                    "stringvarlist" => Current::StringVarList,
+                    "triggers" => Current::Triggers,
+                    "datamappings" => Current::DataMappings,
+                    "inputmappings" => Current::InputMappings,
+                    "triggerhotkeys" => Current::TriggerHotkeys,
+                    "mqtttopics" => Current::MqttTopics,
+                    "stringmappings" => Current::StringMappings,
+                    "hotkey" => Current::Hotkey,
+                    "omsi2komsi" => Current::Omsi2Komsi,
+                    _ => {
+                        opl.diag(line_no, format!("unknown section [{}] is ignored", header));
+                        Current::Ignored
+                    }
+                };
+
+                if let Some((_, first)) = seen.iter().find(|(name, _)| *name == header) {
+                    let first = *first;
+                    // a second list would shift the indices OMSI uses, so only the first one counts
+                    if matches!(
+                        current,
+                        Current::VarList
+                            | Current::SystemVarList
+                            | Current::StringVarList
+                            | Current::Triggers
+                    ) {
+                        opl.diag(
+                            line_no,
+                            format!(
+                                "[{}] is already defined in line {} and is ignored",
+                                header, first
+                            ),
+                        );
+                        current = Current::Ignored;
+                    } else {
+                        opl.diag(
+                            line_no,
+                            format!(
+                                "[{}] is already defined in line {}, the entries are merged",
+                                header, first
+                            ),
+                        );
+                    }
+                } else {
+                    match current {
+                        Current::VarList => opl.varlist.line = line_no,
+                        Current::SystemVarList => opl.systemvarlist.line = line_no,
+                        Current::StringVarList => opl.stringvarlist.line = line_no,
+                        Current::Triggers => opl.triggers.line = line_no,
+                        _ => {}
+                    }
+                    seen.push((header, line_no));
+                }
+                continue;
+            }
+
+            match current {
+                Current::Outside => {
+                    opl.diag(line_no, format!("'{}' is outside of any section", line));
+                    current = Current::Ignored;
+                }
+                Current::Ignored => {}
+                Current::Dll => opl.dll.push(line.to_string()),
+                Current::VarList => {
+                    opl.varlist
+                        .push(line, line_no, "varlist", &mut opl.diagnostics)
+                }
+                Current::SystemVarList => {
+                    opl.systemvarlist
+                        .push(line, line_no, "systemvarlist", &mut opl.diagnostics)
+                }
+                Current::StringVarList => {
+                    opl.stringvarlist
+                        .push(line, line_no, "stringvarlist", &mut opl.diagnostics)
+                }
+                Current::Triggers => {
+                    opl.triggers
+                        .push(line, line_no, "triggers", &mut opl.diagnostics)
+                }
+                Current::Hotkey => match parse_hotkey(line) {
+                    Some(h) => opl.hotkey = h,
+                    None => opl.diag(line_no, format!("invalid hotkey '{}'", line)),
+                },
+                Current::DataMappings
+                | Current::InputMappings
+                | Current::TriggerHotkeys
+                | Current::MqttTopics
+                | Current::StringMappings
+                | Current::Profile
+                | Current::Port(_)
+                | Current::Omsi2Komsi => {
+                    // split at the first '=' only, expressions may contain '==' themselves
+                    let entry = match line.split_once('=') {
+                        Some((key, value)) if !key.trim().is_empty() => Entry {
+                            key: key.trim().to_string(),
+                            value: value.trim().to_string(),
+                            line: line_no,
+                        },
+                        _ => {
+                            opl.diag(line_no, format!("expected 'key = value', found '{}'", line));
+                            continue;
+                        }
+                    };
+                    match current {
+                        Current::DataMappings => opl.datamappings.push(entry),
+                        Current::InputMappings => opl.inputmappings.push(entry),
+                        Current::TriggerHotkeys => opl.triggerhotkeys.push(entry),
+                        Current::MqttTopics => opl.mqtttopics.push(entry),
+                        Current::StringMappings => match StringMapping::parse(&entry) {
+                            Some(mapping) => opl.stringmappings.push(mapping),
+                            None => opl.diag(
+                                line_no,
+                                format!(
+                                    "invalid string mapping '{}', expected 'letter = stringvar[, maxlength]'",
+                                    line
+                                ),
+                            ),
+                        },
+                        Current::Omsi2Komsi => settings.push(entry),
+                        Current::Port(idx) => port_sections[idx].2.push(entry),
+                        _ => {
+                            if let Some(profile) = opl.profiles.last_mut() {
+                                profile.push(entry, &mut opl.diagnostics);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        opl.apply_settings(&settings);
+
+        for (name, line, entries) in port_sections {
+            let mut port = PortSettings::new(&name, line, "", opl.settings.baudrate);
+            for entry in entries {
+                port.push(entry, &mut opl.diagnostics);
+            }
+            if port.portname.is_empty() {
+                opl.diag(
+                    line,
+                    format!("[port.{}] has no portname and is not used", name),
+                );
+                continue;
+            }
+            opl.ports.push(port);
+        }
+        if !opl.ports.is_empty() {
+            for entry in settings
+                .iter()
+                .filter(|e| e.key.to_lowercase().starts_with("portname"))
+            {
+                opl.diag(
+                    entry.line,
+                    format!("'{}' is ignored, the [port.N] sections are used", entry.key),
+                );
+            }
+        }
+
+        // OMSI would read the following lines as names, so the indices no longer match the config
+        let lists = [
+            (&opl.varlist, "varlist"),
+            (&opl.systemvarlist, "systemvarlist"),
+            (&opl.stringvarlist, "stringvarlist"),
+            (&opl.triggers, "triggers"),
+        ];
+        let mut mismatches = Vec::new();
+        for (list, section) in lists {
+            if let Some(count) = list.count
+                && list.len() < count
+            {
+                mismatches.push(Diagnostic {
+                    line: list.line,
+                    message: format!(
+                        "[{}] announces {} names but lists only {}",
+                        section,
+                        count,
+                        list.len()
+                    ),
+                });
+            }
+        }
+        opl.diagnostics.extend(mismatches);
+        opl.diagnostics.sort_by_key(|d| d.line);
+        opl.omsi2komsi = settings;
+        opl
+    }
+
+    /// The serial ports to use, the `[port.N]` sections or else `portname` to `portname5`.
+    pub fn serial_ports(&self) -> Vec<PortSettings> {
+        if !self.ports.is_empty() {
+            return self.ports.clone();
+        }
+        self.settings
+            .portnames
+            .iter()
+            .map(|portname| PortSettings::new("portname", 0, portname, self.settings.baudrate))
+            .collect()
+    }
+
+    /// The system variables followed by the user variables, OMSI numbers the variables in this order.
+    pub fn var_names(&self) -> Vec<String> {
+        let mut names = self.systemvarlist.names.clone();
+        names.extend(self.varlist.names.iter().cloned());
+        names
+    }
+
+    /// Checks the mappings against the lists and returns every problem found.
+    ///
+    /// `is_field` tells whether a lowercase `[datamappings]` target exists, `max_vars` is the number of
+    /// variables the plugin can store.
+    pub fn validate(&self, is_field: &dyn Fn(&str) -> bool, max_vars: usize) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let var_names = self.var_names();
+        let var_index = |name: &str| var_names.iter().position(|n| n.eq_ignore_ascii_case(name));
+
+        let check_var =
+            |name: &str, line: usize, diagnostics: &mut Vec<Diagnostic>| match var_index(name) {
+                None => diagnostics.push(Diagnostic {
+                    line,
+                    message: format!("variable '{}' is not in [systemvarlist] or [varlist]", name),
+                }),
+                Some(idx) if idx >= max_vars => diagnostics.push(Diagnostic {
+                    line,
+                    message: format!(
+                        "variable '{}' has index {}, only the first {} variables can be used",
+                        name, idx, max_vars
+                    ),
+                }),
+                Some(_) => {}
+            };
+
+        let mut sections = vec![("datamappings".to_string(), &self.datamappings)];
+        for profile in &self.profiles {
+            sections.push((format!("profile:{}", profile.name), &profile.mappings));
+        }
+        for (section, mappings) in sections {
+            check_duplicates(mappings, &section, &mut diagnostics);
+            for entry in mappings {
+                if !is_field(&entry.key.to_lowercase()) {
+                    diagnostics.push(Diagnostic {
+                        line: entry.line,
+                        message: format!("unknown field '{}' in [{}]", entry.key, section),
+                    });
+                    continue;
+                }
+                if entry.value.is_empty() {
+                    diagnostics.push(Diagnostic {
+                        line: entry.line,
+                        message: format!("'{}' has no source", entry.key),
+                    });
+                    continue;
+                }
+                // only the syntax here, the variables are checked below with their own message
+                let is_plain = entry
+                    .value
+                    .split(',')
+                    .all(|p| expr::is_identifier(p.trim()));
+                if !is_plain && let Err(e) = Expr::parse(&entry.value.to_lowercase(), &|_| Some(0))
+                {
+                    diagnostics.push(Diagnostic {
+                        line: entry.line,
+                        message: format!("invalid expression for '{}': {}", entry.key, e),
+                    });
+                    continue;
+                }
+                for name in source_variables(&entry.value) {
+                    check_var(name, entry.line, &mut diagnostics);
+                }
+            }
+        }
+
+        for profile in &self.profiles {
+            match &profile.string_var {
+                Some(entry) if self.stringvarlist.position(&entry.value).is_none() => diagnostics
+                    .push(Diagnostic {
+                        line: entry.line,
+                        message: format!(
+                            "string variable '{}' is not in [stringvarlist]",
+                            entry.value
+                        ),
+                    }),
+                Some(_) => {}
+                None => diagnostics.push(Diagnostic {
+                    line: profile.line,
+                    message: format!(
+                        "profile '{}' has no stringvar and is never selected",
+                        profile.name
+                    ),
+                }),
+            }
+        }
+
+        check_duplicates(&self.inputmappings, "inputmappings", &mut diagnostics);
+        for entry in &self.inputmappings {
+            if is_plugin_action(&entry.value) {
+                continue;
+            }
+            if var_index(&entry.value).is_some() {
+                check_var(&entry.value, entry.line, &mut diagnostics);
+                continue;
+            }
+            match self.triggers.position(&entry.value) {
+                Some(idx) if idx >= max_vars => diagnostics.push(Diagnostic {
+                    line: entry.line,
+                    message: format!(
+                        "trigger '{}' has index {}, only the first {} triggers can be used",
+                        entry.value, idx, max_vars
+                    ),
+                }),
+                Some(_) => {}
+                None => diagnostics.push(Diagnostic {
+                    line: entry.line,
+                    message: format!(
+                        "'{}' is neither in [systemvarlist], [varlist] nor [triggers]",
+                        entry.value
+                    ),
+                }),
+            }
+        }
+
+        check_duplicates(&self.triggerhotkeys, "triggerhotkeys", &mut diagnostics);
+        for entry in &self.triggerhotkeys {
+            if self.triggers.position(&entry.key).is_none() && !is_plugin_action(&entry.key) {
+                diagnostics.push(Diagnostic {
+                    line: entry.line,
+                    message: format!("trigger '{}' is not in [triggers]", entry.key),
+                });
+            }
+            if parse_hotkey(&entry.value).is_none() {
+                diagnostics.push(Diagnostic {
+                    line: entry.line,
+                    message: format!("invalid hotkey '{}'", entry.value),
+                });
+            }
+        }
+
+        check_duplicates(&self.mqtttopics, "mqtttopics", &mut diagnostics);
+
+        let mut letters = Vec::new();
+        for (i, letter) in self.settings.trip_commands.iter().enumerate() {
+            let setting = format!("trip{}command", i + 1);
+            if let Some(letter) = letter {
+                letters.push((*letter, self.setting_line(&setting), setting));
+            }
+        }
+        if let Some(letter) = self.settings.reverse_command {
+            letters.push((
+                letter,
+                self.setting_line("reversecommand"),
+                "reversecommand".to_string(),
+            ));
+        }
+        for mapping in &self.stringmappings {
+            letters.push((mapping.letter, mapping.line, "[stringmappings]".to_string()));
+        }
+        check_command_letters(&letters, &mut diagnostics);
+
+        for mapping in &self.stringmappings {
+            if self.stringvarlist.position(&mapping.string_var).is_none() {
+                diagnostics.push(Diagnostic {
+                    line: mapping.line,
+                    message: format!("'{}' is not in [stringvarlist]", mapping.string_var),
+                });
+            }
+        }
+
+        diagnostics.sort_by_key(|d| d.line);
+        diagnostics
+    }
+
+    /// The line of a `[omsi2komsi]` setting, the last one wins like in `apply_settings`.
+    fn setting_line(&self, key: &str) -> usize {
+        self.omsi2komsi
+            .iter()
+            .rev()
+            .find(|e| e.key.eq_ignore_ascii_case(key))
+            .map_or(0, |e| e.line)
+    }
+
+    fn diag(&mut self, line: usize, message: String) {
+        self.diagnostics.push(Diagnostic { line, message });
+    }
+
+    fn apply_settings(&mut self, entries: &[Entry]) {
+        let mut portnames: [Option<String>; 5] = Default::default();
+
+        for entry in entries {
+            let key = entry.key.to_lowercase();
+            let value = entry.value.as_str();
+            let ok = match key.as_str() {
+                "portname" | "portname2" | "portname3" | "portname4" | "portname5" => {
+                    let slot = key[8..].parse::<usize>().unwrap_or(1) - 1;
+                    portnames[slot] = Some(value.to_string()).filter(|p| !p.is_empty());
+                    PortSelector::parse(value).is_some()
+                }
+                "baudrate" => set(&mut self.settings.baudrate, value.parse().ok()),
+                "serialportenabled" => {
+                    set(&mut self.settings.serial_port_enabled, parse_bool(value))
+                }
+                "debug" => set(&mut self.settings.debug, parse_bool(value)),
+                "record" => set(&mut self.settings.record, parse_bool(value)),
+                "engineonvalue" => set(&mut self.settings.engine_on_value, value.parse().ok()),
+                "threshold" => set(&mut self.settings.threshold, value.parse().ok()),
+                "indicatormode" => set(
+                    &mut self.settings.indicator_mode,
+                    IndicatorMode::from_name(value),
+                ),
+                "enginemode" => set(&mut self.settings.engine_mode, EngineMode::from_name(value)),
+                "enginerpm" => set(&mut self.settings.engine_rpm, value.parse().ok()),
+                "hazardmode" => set(&mut self.settings.hazard_mode, HazardMode::from_name(value)),
+                "hazardlatch" => set(&mut self.settings.hazard_latch_ms, value.parse().ok()),
+                "blinkhold" => set(&mut self.settings.blink_hold_ms, value.parse().ok()),
+                "speedunit" => set(&mut self.settings.speed_unit, SpeedUnit::from_name(value)),
+                "speedscale" => set(&mut self.settings.speed_scale, value.parse().ok()),
+                "speedrounding" => set(
+                    &mut self.settings.speed_rounding,
+                    Rounding::from_name(value),
+                ),
+                "reversecommand" => set(
+                    &mut self.settings.reverse_command,
+                    parse_command_letter(value),
+                ),
+                "resyncinterval" => set(&mut self.settings.resync_interval_s, value.parse().ok()),
+                "udptargets" => set(&mut self.settings.udp_targets, parse_host_ports(value)),
+                "tcplisten" => set(
+                    &mut self.settings.tcp_listen,
+                    parse_host_ports(value)
+                        .filter(|t| t.len() <= 1)
+                        .map(|mut t| t.pop()),
+                ),
+                "weblisten" => set(
+                    &mut self.settings.web_listen,
+                    parse_host_ports(value)
+                        .filter(|t| t.len() <= 1)
+                        .map(|mut t| t.pop()),
+                ),
+                "webroot" => set(
+                    &mut self.settings.web_root,
+                    Some(value.to_string()).filter(|v| !v.is_empty()),
+                ),
+                "mqttbroker" => set(
+                    &mut self.settings.mqtt_broker,
+                    parse_host_ports(value)
+                        .filter(|t| t.len() <= 1)
+                        .map(|mut t| t.pop()),
+                ),
+                "mqtttopic" => set(
+                    &mut self.settings.mqtt_topic,
+                    Some(value.trim_end_matches('/').to_string())
+                        .filter(|v| !v.is_empty() && !v.contains(['+', '#'])),
+                ),
+                "stringencoding" => set(
+                    &mut self.settings.text_encoding,
+                    TextEncoding::from_name(value),
+                ),
+                "stringmaxlength" => set(&mut self.settings.text_max_len, value.parse().ok()),
+                "mqttclientid" => set(
+                    &mut self.settings.mqtt_client_id,
+                    Some(value.to_string()).filter(|v| !v.is_empty()),
+                ),
+                "mqttuser" => set(
+                    &mut self.settings.mqtt_user,
+                    Some(Some(value.to_string()).filter(|v| !v.is_empty())),
+                ),
+                "mqttpassword" => set(
+                    &mut self.settings.mqtt_password,
+                    Some(Some(value.to_string()).filter(|v| !v.is_empty())),
+                ),
+                "trip1command" => set(
+                    &mut self.settings.trip_commands[0],
+                    parse_command_letter(value),
+                ),
+                "trip2command" => set(
+                    &mut self.settings.trip_commands[1],
+                    parse_command_letter(value),
+                ),
+                _ => {
+                    self.diag(entry.line, format!("unknown setting '{}'", entry.key));
+                    continue;
+                }
+            };
+            if !ok {
+                self.diag(
+                    entry.line,
+                    format!("invalid value '{}' for '{}'", value, entry.key),
+                );
+            }
+        }
+
+        let portnames: Vec<String> = portnames.into_iter().flatten().collect();
+        if !portnames.is_empty() {
+            self.settings.portnames = portnames;
+        }
+    }
+}
+
+/// Reports keys that appear more than once, keys are compared without case.
+fn check_duplicates(entries: &[Entry], section: &str, diagnostics: &mut Vec<Diagnostic>) {
+    for (i, entry) in entries.iter().enumerate() {
+        if let Some(first) = entries[..i]
+            .iter()
+            .find(|e| e.key.eq_ignore_ascii_case(&entry.key))
+        {
+            diagnostics.push(Diagnostic {
+                line: entry.line,
+                message: format!(
+                    "duplicate mapping for '{}' in [{}], first mapped in line {}",
+                    entry.key, section, first.line
+                ),
+            });
+        }
+    }
+}
+
+/// Returns the variable names used by a mapping source, either a comma separated list or an expression.
+///
+/// Names followed by `(` are functions and left out.
+pub fn source_variables(source: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = source;
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if !rest[end..].trim_start().starts_with('(') {
+                names.push(&rest[..end]);
+            }
+            rest = &rest[end..];
+        } else if c.is_ascii_digit() || c == '.' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            rest = &rest[end..];
+        } else {
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    names
+}
+
+fn set<T>(target: &mut T, value: Option<T>) -> bool {
+    match value {
+        Some(v) => {
+            *target = v;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Tells whether `name` is one of the [`TRIP_RESETS`] or [`RESYNC`], which the plugin handles itself.
+pub fn is_plugin_action(name: &str) -> bool {
+    TRIP_RESETS.iter().any(|r| r.eq_ignore_ascii_case(name)) || RESYNC.eq_ignore_ascii_case(name)
+}
+
+/// Letters the KOMSI protocol already uses, the hardware would take a configurable command sent with
+/// one of them for the KOMSI command.
+pub const KOMSI_LETTERS: &str = "ABCDEFGHIJKLMNOPdioprstuvwxyz";
+
+/// Reports configurable command letters that KOMSI already uses or that are used twice.
+///
+/// `letters` are the letter, its line and the setting or section it comes from.
+fn check_command_letters(letters: &[(char, usize, String)], diagnostics: &mut Vec<Diagnostic>) {
+    for (i, (letter, line, source)) in letters.iter().enumerate() {
+        if KOMSI_LETTERS.contains(*letter) {
+            diagnostics.push(Diagnostic {
+                line: *line,
+                message: format!(
+                    "'{}' of {} is a KOMSI command letter and is not sent",
+                    letter, source
+                ),
+            });
+        } else if let Some((_, first_line, first)) =
+            letters[..i].iter().find(|(l, _, _)| l == letter)
+        {
+            diagnostics.push(Diagnostic {
+                line: *line,
+                message: format!(
+                    "'{}' of {} is already used by {} in line {}",
+                    letter, source, first, first_line
+                ),
+            });
+        }
+    }
+}
+
+/// Parses a KOMSI command letter, an empty value means the command is not sent.
+fn parse_command_letter(s: &str) -> Option<Option<char>> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (None, _) => Some(None),
+        (Some(c), None) if c.is_ascii_alphabetic() => Some(Some(c)),
+        _ => None,
+    }
+}
+
+/// Parses a comma separated list of `host:port` addresses, the host is resolved later.
+fn parse_host_ports(s: &str) -> Option<Vec<String>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            let (host, port) = t.rsplit_once(':')?;
+            (!host.is_empty() && port.parse::<u16>().is_ok()).then(|| t.to_string())
+        })
+        .collect()
+}
+
+/// Parses `true`/`false`, `yes`/`no`, `on`/`off` and `1`/`0`.
+pub fn parse_bool(s: &str) -> Option<bool> {
+    match s.trim().to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a virtual key code, either hex with `0x` or decimal.
+pub fn parse_hotkey(s: &str) -> Option<u32> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u32>().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+[dll]
+omsi2komsi.dll
+
+[varlist]
+3
+elec_busbar_main
+Velocity
+door_light_1
+
+[systemvarlist]
+2
+Time
+Day
+
+[stringvarlist]
+1
+kennzeichen
+
+[omsi2komsi]
+portname = com8
+portname2 =
+portname3 = com9
+baudrate = 9600
+serialportenabled = true
+debug=true
+threshold = 0.25
+indicatormode = Separate
+hazardmode = both
+hazardlatch = 600
+blinkhold = 800
+trip1command = q
+speedunit = mph
+speedscale = 1.05
+speedrounding = round
+reversecommand = R
+resyncinterval = 30
+udptargets = 192.168.1.255:5005, cockpit-pc:5005
+tcplisten = 0.0.0.0:5006
+weblisten = 127.0.0.1:8080
+
+[datamappings]
+Ignition=elec_busbar_main
+; a comment
+MainLights=ai_light == 1
+
+[profile:Citaro]
+stringvar = kennzeichen
+match = *citaro*
+enginemode = rpm
+enginerpm = 250
+
+[hotkey]
+0x78
+";
+
+    #[test]
+    fn parses_all_sections() {
+        let opl = Opl::parse(SAMPLE);
+        assert_eq!(opl.diagnostics, vec![]);
+        assert_eq!(opl.dll, vec!["omsi2komsi.dll"]);
+        assert_eq!(opl.varlist.count, Some(3));
+        assert_eq!(
+            opl.varlist.names,
+            vec!["elec_busbar_main", "Velocity", "door_light_1"]
+        );
+        assert_eq!(opl.varlist.lines, vec![6, 7, 8]);
+        assert_eq!(opl.systemvarlist.names, vec!["Time", "Day"]);
+        assert_eq!(opl.stringvarlist.names, vec!["kennzeichen"]);
+        assert_eq!(opl.hotkey, 0x78);
+        assert_eq!(opl.profiles.len(), 1);
+        assert_eq!(opl.profiles[0].name, "Citaro");
+        assert_eq!(
+            opl.profiles[0].string_var.as_ref().unwrap().value,
+            "kennzeichen"
+        );
+        assert_eq!(opl.profiles[0].pattern, "*citaro*");
+        assert_eq!(opl.profiles[0].engine_mode, Some(EngineMode::Rpm));
+        assert_eq!(opl.profiles[0].engine_rpm, Some(250.0));
+        assert!(opl.profiles[0].mappings.is_empty());
+    }
+
+    #[test]
+    fn var_names_start_with_system_variables() {
+        let opl = Opl::parse(SAMPLE);
+        assert_eq!(
+            opl.var_names(),
+            vec![
+                "Time",
+                "Day",
+                "elec_busbar_main",
+                "Velocity",
+                "door_light_1"
+            ]
+        );
+    }
+
+    #[test]
+    fn lookup_ignores_case() {
+        let opl = Opl::parse(SAMPLE);
+        assert_eq!(opl.varlist.position("velocity"), Some(1));
+        assert_eq!(opl.varlist.position("VELOCITY"), Some(1));
+        assert_eq!(opl.varlist.position("unknown"), None);
+    }
+
+    #[test]
+    fn settings_are_typed() {
+        let opl = Opl::parse(SAMPLE);
+        assert_eq!(opl.settings.portnames, vec!["com8", "com9"]);
+        assert_eq!(opl.settings.baudrate, 9600);
+        assert!(opl.settings.serial_port_enabled);
+        assert!(opl.settings.debug);
+        assert_eq!(opl.settings.engine_on_value, 1);
+        assert_eq!(opl.settings.threshold, 0.25);
+        assert_eq!(opl.settings.indicator_mode, IndicatorMode::Separate);
+        assert_eq!(opl.settings.engine_mode, EngineMode::Battery);
+        assert_eq!(opl.settings.hazard_mode, HazardMode::Both);
+        assert_eq!(opl.settings.hazard_latch_ms, 600);
+        assert_eq!(opl.settings.blink_hold_ms, 800);
+        assert_eq!(opl.settings.trip_commands, [Some('q'), None]);
+        assert_eq!(opl.settings.speed_unit, SpeedUnit::Mph);
+        assert_eq!(opl.settings.speed_scale, 1.05);
+        assert_eq!(opl.settings.speed_rounding, Rounding::Round);
+        assert_eq!(opl.settings.reverse_command, Some('R'));
+        assert_eq!(opl.settings.resync_interval_s, 30);
+        assert_eq!(
+            opl.settings.udp_targets,
+            vec!["192.168.1.255:5005", "cockpit-pc:5005"]
+        );
+        assert_eq!(opl.settings.tcp_listen.as_deref(), Some("0.0.0.0:5006"));
+        assert_eq!(opl.settings.web_listen.as_deref(), Some("127.0.0.1:8080"));
+        assert_eq!(opl.settings.web_root, "omsi2komsi_web");
+    }
+
+    #[test]
+    fn defaults_without_file_content() {
+        let opl = Opl::parse("");
+        assert_eq!(opl, Opl::default());
+        assert_eq!(opl.settings.portnames, vec!["com1"]);
+        assert_eq!(opl.hotkey, DEFAULT_HOTKEY);
+        assert_eq!(opl.settings.indicator_mode, IndicatorMode::Combined);
+    }
+
+    #[test]
+    fn mappings_split_at_first_equals_sign() {
+        let opl = Opl::parse(SAMPLE);
+        assert_eq!(opl.datamappings.len(), 2);
+        assert_eq!(opl.datamappings[1].key, "MainLights");
+        assert_eq!(opl.datamappings[1].value, "ai_light == 1");
+        assert_eq!(opl.datamappings[1].line, 44);
+    }
+
+    #[test]
+    fn names_beyond_count_are_ignored() {
+        let opl = Opl::parse("[varlist]\n1\na\nb\n");
+        assert_eq!(opl.varlist.names, vec!["a"]);
+        assert_eq!(opl.diagnostics.len(), 1);
+        assert_eq!(opl.diagnostics[0].line, 4);
+    }
+
+    #[test]
+    fn missing_count_line_is_reported() {
+        let opl = Opl::parse("[triggers]\nbus_doorfront0\n");
+        assert_eq!(opl.triggers.count, None);
+        assert_eq!(opl.triggers.names, vec!["bus_doorfront0"]);
+        assert_eq!(opl.diagnostics[0].line, 2);
+    }
+
+    #[test]
+    fn zero_count_allows_no_names() {
+        let opl = Opl::parse("[stringvarlist]\n0\nkennzeichen\n");
+        assert!(opl.stringvarlist.is_empty());
+        assert_eq!(opl.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn invalid_lines_are_reported_with_line_numbers() {
+        let opl = Opl::parse(
+            "stray\n[omsi2komsi]\nbaudrate = fast\nfoo = 1\n[datamappings]\nIgnition\n[hotkey]\nF10\n[unknown]\nx\n[broken\n",
+        );
+        let lines: Vec<usize> = opl.diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![1, 3, 4, 6, 8, 9, 11]);
+        assert_eq!(opl.settings.baudrate, 115200);
+        assert_eq!(opl.hotkey, DEFAULT_HOTKEY);
+    }
+
+    #[test]
+    fn duplicate_lists_keep_the_first() {
+        let opl = Opl::parse("[varlist]\n1\na\n[varlist]\n1\nb\n");
+        assert_eq!(opl.varlist.names, vec!["a"]);
+        assert_eq!(opl.diagnostics.len(), 1);
+        assert_eq!(opl.diagnostics[0].line, 4);
+        assert_eq!(
+            opl.diagnostics[0].to_string(),
+            "line 4: [varlist] is already defined in line 1 and is ignored"
+        );
+    }
+
+    #[test]
+    fn section_names_ignore_case_and_bom() {
+        let opl = Opl::parse("\u{feff}[VarList]\n1\na\n[HOTKEY]\n121\n");
+        assert_eq!(opl.diagnostics, vec![]);
+        assert_eq!(opl.varlist.names, vec!["a"]);
+        assert_eq!(opl.hotkey, 121);
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let opl = Opl::parse("[varlist]\r\n1\r\nVelocity\r\n[omsi2komsi]\r\ndebug = yes\r\n");
+        assert_eq!(opl.varlist.names, vec!["Velocity"]);
+        assert!(opl.settings.debug);
+    }
+
+    #[test]
+    fn hotkey_formats() {
+        assert_eq!(parse_hotkey("0x79"), Some(0x79));
+        assert_eq!(parse_hotkey("0X7a"), Some(0x7a));
+        assert_eq!(parse_hotkey("121"), Some(121));
+        assert_eq!(parse_hotkey("F10"), None);
+    }
+
+    #[test]
+    fn count_mismatch_is_reported() {
+        let opl = Opl::parse("[varlist]\n3\na\nb\n\n[hotkey]\n0x79\n");
+        assert_eq!(opl.diagnostics.len(), 1);
+        assert_eq!(
+            opl.diagnostics[0].to_string(),
+            "line 1: [varlist] announces 3 names but lists only 2"
+        );
+    }
+
+    fn is_field(name: &str) -> bool {
+        matches!(
+            name,
+            "ignition" | "speed" | "passengerdoorsopen" | "mainlights"
+        )
+    }
+
+    #[test]
+    fn validate_reports_mapping_problems() {
+        let opl = Opl::parse(
+            "\
+[varlist]
+3
+elec_busbar_main
+Velocity
+ai_light
+[datamappings]
+Ignition=elec_busbar_main
+PassengerDoorOpen=door_light_1
+Speed=abs(velocity) * tempo
+Ignition=velocity
+MainLights=ai_light
+",
+        );
+        assert_eq!(opl.diagnostics, vec![]);
+        let problems = opl.validate(&is_field, 2);
+        let messages: Vec<String> = problems.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "line 8: unknown field 'PassengerDoorOpen' in [datamappings]",
+                "line 9: variable 'tempo' is not in [systemvarlist] or [varlist]",
+                "line 10: duplicate mapping for 'Ignition' in [datamappings], first mapped in line 7",
+                "line 11: variable 'ai_light' has index 2, only the first 2 variables can be used",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_accepts_operators_and_inversion() {
+        let opl = Opl::parse(
+            "\
+[varlist]
+2
+door_light_1
+batt_ok
+[datamappings]
+Ignition=batt_ok >= 1
+MainLights=!batt_ok
+Speed=door_light_1 >
+PassengerDoorsOpen=
+",
+        );
+        let messages: Vec<String> = opl
+            .validate(&is_field, 30)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "line 8: invalid expression for 'Speed': unexpected end of expression",
+                "line 9: 'PassengerDoorsOpen' has no source",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_profiles_inputs_and_hotkeys() {
+        let opl = Opl::parse(
+            "\
+[varlist]
+1
+haltewunsch
+[stringvarlist]
+1
+kennzeichen
+[triggers]
+1
+bus_doorfront0
+[profile:a]
+stringvar = wagennummer
+speed = tempo
+[profile:b]
+match = *
+[inputmappings]
+StopRequest=haltewunsch
+FrontDoor=bus_doorfront0
+SecondDoor=bus_doorfront1
+[triggerhotkeys]
+bus_doorfront0=0x70
+bus_doorfront1=F1
+",
+        );
+        assert_eq!(opl.diagnostics, vec![]);
+        let lines: Vec<usize> = opl.validate(&is_field, 30).iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![11, 12, 13, 18, 21, 21]);
+    }
+
+    #[test]
+    fn port_sections() {
+        let opl = Opl::parse(
+            "\
+[omsi2komsi]
+portname = com1
+baudrate = 57600
+[port.tacho]
+portname = usb:2341:0043
+baudrate = 115200
+[port.doors]
+portname = com4
+databits = 7
+parity = even
+stopbits = 2
+flowcontrol = hardware
+timeout = 50
+fields = FrontDoor, SecondDoor,StopRequest
+[port.empty]
+baudrate = 9600
+[port.tacho]
+speed = 1
+",
+        );
+        assert_eq!(opl.ports.len(), 2);
+        let tacho = &opl.ports[0];
+        assert_eq!(tacho.name, "tacho");
+        assert_eq!(tacho.portname, "usb:2341:0043");
+        assert_eq!(tacho.baudrate, 115200);
+        assert_eq!(tacho.data_bits, 8);
+        assert_eq!(tacho.field_names(), None);
+        let doors = &opl.ports[1];
+        assert_eq!(doors.baudrate, 57600);
+        assert_eq!(doors.data_bits, 7);
+        assert_eq!(doors.parity, Parity::Even);
+        assert_eq!(doors.stop_bits, 2);
+        assert_eq!(doors.flow_control, FlowControl::Hardware);
+        assert_eq!(doors.timeout_ms, 50);
+        assert_eq!(
+            doors.field_names(),
+            Some(vec!["FrontDoor", "SecondDoor", "StopRequest"])
+        );
+        assert_eq!(opl.serial_ports(), opl.ports);
+        let lines: Vec<usize> = opl.diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![2, 15, 17, 18]);
+    }
+
+    #[test]
+    fn legacy_portnames_become_ports() {
+        let opl = Opl::parse(SAMPLE);
+        let ports = opl.serial_ports();
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[1].portname, "com9");
+        assert_eq!(ports[1].baudrate, 9600);
+        assert_eq!(ports[1].fields, None);
+        assert!(!opl.settings.record);
+        assert!(Opl::parse("[omsi2komsi]\nrecord = on\n").settings.record);
+    }
+
+    #[test]
+    fn network_addresses_need_a_port() {
+        let opl = Opl::parse("[omsi2komsi]\nudptargets = esp32:5005, 10.0.0.2\n");
+        assert!(opl.settings.udp_targets.is_empty());
+        assert_eq!(opl.diagnostics.len(), 1);
+        let opl = Opl::parse("[omsi2komsi]\nudptargets =\ntcplisten =\n");
+        assert_eq!(opl.diagnostics, vec![]);
+        assert_eq!(opl.settings.tcp_listen, None);
+        let opl = Opl::parse("[omsi2komsi]\ntcplisten = a:1, b:2\n");
+        assert_eq!(opl.diagnostics.len(), 1);
+        let opl = Opl::parse("[omsi2komsi]\nweblisten = 8080\n");
+        assert_eq!(opl.settings.web_listen, None);
+        assert_eq!(opl.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn mqtt_settings() {
+        let opl = Opl::parse("[omsi2komsi]\nmqttbroker = localhost:1883\n");
+        assert_eq!(opl.settings.mqtt_broker.as_deref(), Some("localhost:1883"));
+        assert_eq!(opl.settings.mqtt_topic, "omsi/bus");
+        assert_eq!(opl.settings.mqtt_client_id, "omsi2komsi");
+        assert_eq!(opl.settings.mqtt_user, None);
+
+        let opl = Opl::parse(
+            "[omsi2komsi]\nmqtttopic = cockpit/bus/\nmqttuser = omsi\nmqttpassword = secret\n\
+             [mqtttopics]\nlights_front_door = cockpit/doors/front\nLights_Front_Door = x\n",
+        );
+        assert_eq!(opl.settings.mqtt_topic, "cockpit/bus");
+        assert_eq!(opl.settings.mqtt_user.as_deref(), Some("omsi"));
+        assert_eq!(opl.settings.mqtt_password.as_deref(), Some("secret"));
+        assert_eq!(opl.mqtttopics.len(), 2);
+        assert_eq!(opl.mqtttopics[0].value, "cockpit/doors/front");
+        assert_eq!(opl.validate(&|_| true, 30).len(), 1);
+
+        let opl = Opl::parse("[omsi2komsi]\nmqtttopic = omsi/#\n");
+        assert_eq!(opl.settings.mqtt_topic, "omsi/bus");
+        assert_eq!(opl.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn text_encoding() {
+        let ascii = TextEncoding::Ascii;
+        assert_eq!(ascii.encode(" Hürth, Bahnhof ", 0), b"Huerth, Bahnhof");
+        assert_eq!(ascii.encode("Straße \"Süd\"", 0), b"Strasse 'Sued'");
+        assert_eq!(ascii.encode("Café\nHof", 0), b"Cafe Hof");
+        assert_eq!(ascii.encode("Jägerstraße", 5), b"Jaege");
+        // a transliteration that does not fit is left out as a whole
+        assert_eq!(ascii.encode("Jägerstraße", 2), b"J");
+        assert_eq!(ascii.encode("Łódź 東京", 0), b"Lodz ??");
+
+        let latin1 = TextEncoding::Latin1;
+        assert_eq!(latin1.encode("Hürth", 3), b"H\xfcr");
+        assert_eq!(latin1.encode("Łódź", 0), b"L\xf3dz");
+
+        let utf8 = TextEncoding::Utf8;
+        assert_eq!(utf8.encode("Hürth", 2), "Hü".as_bytes());
+    }
+
+    #[test]
+    fn string_mappings() {
+        let opl = Opl::parse(
+            "\
+[stringvarlist]
+2
+IBIS_busstop_name
+IBIS_terminus_name
+[omsi2komsi]
+stringencoding = latin1
+stringmaxlength = 20
+[stringmappings]
+S = IBIS_busstop_name, 16
+T = ibis_terminus_name
+U = ibis_line
+S = ibis_terminus_name
+ST = ibis_line
+V = ibis_line, long
+",
+        );
+        assert_eq!(opl.settings.text_encoding, TextEncoding::Latin1);
+        assert_eq!(opl.settings.text_max_len, 20);
+        assert_eq!(
+            opl.stringmappings[0],
+            StringMapping {
+                letter: 'S',
+                string_var: "IBIS_busstop_name".to_string(),
+                max_len: Some(16),
+                line: 9,
+            }
+        );
+        assert_eq!(opl.stringmappings[1].max_len, None);
+        assert_eq!(opl.stringmappings.len(), 4);
+        let lines: Vec<usize> = opl.diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![13, 14]);
+        let lines: Vec<usize> = opl.validate(&|_| true, 30).iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![11, 12]);
+    }
+
+    #[test]
+    fn port_selectors() {
+        assert_eq!(
+            PortSelector::parse("com8"),
+            Some(PortSelector::Name("com8".to_string()))
+        );
+        let uno = PortSelector::parse("usb:2341:0043").unwrap();
+        assert!(uno.matches_usb(0x2341, 0x43, None, None));
+        assert!(!uno.matches_usb(0x2341, 0x42, None, None));
+        let with_serial = PortSelector::parse("USB:2341:0043:ab12").unwrap();
+        assert!(with_serial.matches_usb(0x2341, 0x43, Some("AB12"), None));
+        assert!(!with_serial.matches_usb(0x2341, 0x43, Some("cd34"), None));
+        assert!(!with_serial.matches_usb(0x2341, 0x43, None, None));
+        let serial = PortSelector::parse("serial: AB12").unwrap();
+        assert!(serial.matches_usb(1, 2, Some("ab12"), None));
+        let product = PortSelector::parse("product:uno").unwrap();
+        assert!(product.matches_usb(1, 2, None, Some("Arduino Uno")));
+        assert!(!product.matches_usb(1, 2, None, None));
+        assert!(!PortSelector::Name("com8".to_string()).matches_usb(1, 2, None, None));
+        assert_eq!(PortSelector::parse("usb:xyz:1"), None);
+        assert_eq!(PortSelector::parse("serial:"), None);
+        assert_eq!(PortSelector::parse("bluetooth:1"), None);
+
+        let opl = Opl::parse("[omsi2komsi]\nportname = usb:2341\nportname2 = product:Uno\n");
+        assert_eq!(opl.settings.portnames, vec!["usb:2341", "product:Uno"]);
+        assert_eq!(
+            opl.diagnostics,
+            vec![Diagnostic {
+                line: 2,
+                message: "invalid value 'usb:2341' for 'portname'".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn speed_conversion() {
+        assert_eq!(SpeedUnit::Kmh.from_kmh(50.0), 50.0);
+        assert_eq!(SpeedUnit::Ms.from_kmh(36.0), 10.0);
+        assert!((SpeedUnit::Mph.from_kmh(100.0) - 62.137).abs() < 0.001);
+        assert_eq!(Rounding::Floor.apply(49.7), 49.0);
+        assert_eq!(Rounding::Round.apply(49.5), 50.0);
+        assert_eq!(Rounding::Ceil.apply(49.1), 50.0);
+    }
+
+    #[test]
+    fn trip_resets_and_commands() {
+        let opl = Opl::parse(
+            "\
+[omsi2komsi]
+trip2command = n
+trip1command = qq
+[inputmappings]
+StopBrake=TripReset1
+InfoRequest=resync
+[triggerhotkeys]
+tripreset2=0x71
+Resync=0x72
+",
+        );
+        assert_eq!(opl.settings.trip_commands, [None, Some('n')]);
+        assert_eq!(
+            opl.diagnostics,
+            vec![Diagnostic {
+                line: 3,
+                message: "invalid value 'qq' for 'trip1command'".to_string(),
+            }]
+        );
+        assert_eq!(opl.validate(&is_field, 30), vec![]);
+    }
+
+    #[test]
+    fn command_letters_must_be_free() {
+        let opl = Opl::parse("[omsi2komsi]\ntrip1command = y\ntrip2command = q\n");
+        assert_eq!(
+            opl.validate(&is_field, 30),
+            vec![Diagnostic {
+                line: 2,
+                message: "'y' of trip1command is a KOMSI command letter and is not sent"
+                    .to_string(),
+            }]
+        );
+        let opl = Opl::parse("[omsi2komsi]\ntrip1command = q\ntrip2command = q\n");
+        assert_eq!(
+            opl.validate(&is_field, 30),
+            vec![Diagnostic {
+                line: 3,
+                message: "'q' of trip2command is already used by trip1command in line 2"
+                    .to_string(),
+            }]
+        );
+        // Q is free, the letters are case sensitive
+        let opl = Opl::parse("[omsi2komsi]\ntrip1command = q\ntrip2command = Q\n");
+        assert_eq!(opl.validate(&is_field, 30), vec![]);
+
+        let opl = Opl::parse("[omsi2komsi]\nreversecommand = B\n");
+        assert_eq!(
+            opl.validate(&is_field, 30),
+            vec![Diagnostic {
+                line: 2,
+                message: "'B' of reversecommand is a KOMSI command letter and is not sent"
+                    .to_string(),
+            }]
+        );
+        let opl = Opl::parse("[omsi2komsi]\ntrip1command = R\nreversecommand = R\n");
+        assert_eq!(
+            opl.validate(&is_field, 30),
+            vec![Diagnostic {
+                line: 3,
+                message: "'R' of reversecommand is already used by trip1command in line 2"
+                    .to_string(),
+            }]
+        );
+
+        let opl = Opl::parse(
+            "[stringvarlist]\n1\nline\n[omsi2komsi]\ntrip1command = S\n\
+             [stringmappings]\nS = line\ny = line\nS = line\n",
+        );
+        assert_eq!(
+            opl.validate(&is_field, 30),
+            vec![
+                Diagnostic {
+                    line: 7,
+                    message: "'S' of [stringmappings] is already used by trip1command in line 5"
+                        .to_string(),
+                },
+                Diagnostic {
+                    line: 8,
+                    message: "'y' of [stringmappings] is a KOMSI command letter and is not sent"
+                        .to_string(),
+                },
+                Diagnostic {
+                    line: 9,
+                    message: "'S' of [stringmappings] is already used by trip1command in line 5"
+                        .to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn source_variables_skip_functions_and_numbers() {
+        assert_eq!(
+            source_variables("ai_blinker_l, ai_blinker_r"),
+            vec!["ai_blinker_l", "ai_blinker_r"]
+        );
+        assert_eq!(source_variables("max(a, 2.5) + b_1 / 10"), vec!["a", "b_1"]);
+        assert_eq!(source_variables("round (x)"), vec!["x"]);
+        assert!(source_variables("").is_empty());
+    }
+
+    #[test]
+    fn shipped_files_parse_cleanly() {
+        for content in [
+            include_str!("../../omsi2komsi.opl"),
+            include_str!("../../omsilogger.opl"),
+        ] {
+            // validated with the real field names in omsi_2_komsi
+            assert_eq!(Opl::parse(content).diagnostics, vec![]);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Plays a recording of omsi2komsi back without OMSI, with omsi2komsi.opl from the current directory.
+//!
+//! ```text
+//! omsi2komsi-replay omsi2komsi_2026-10-18_14-30-00.rec [--speed 2] [--repeat]
+//! ```
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: omsi2komsi-replay <recording> [--speed <factor>] [--repeat]";
+
+fn main() -> ExitCode {
+    let mut path: Option<PathBuf> = None;
+    let mut speed = 1.0;
+    let mut repeat = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--speed" => match args.next().and_then(|s| s.parse::<f64>().ok()) {
+                Some(s) if s > 0.0 => speed = s,
+                _ => {
+                    eprintln!("--speed needs a factor above 0");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--repeat" => repeat = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(PathBuf::from(arg)),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    match omsi2komsi::replay(&path, speed, repeat) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
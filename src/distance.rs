@@ -0,0 +1,206 @@
+//! Distance counter with trip counters, kept across sessions in a small state file.
+
+use std::time::{Duration, Instant};
+
+// longer gaps between two updates (e.g. a paused game) are not integrated
+const MAX_STEP: Duration = Duration::from_secs(1);
+// odometer jumps above this are a bus change or a reset, not driven distance
+const MAX_ODOMETER_STEP_M: f64 = 500.0;
+
+/// Total and trip distances in metres.
+pub struct Distance {
+    /// distance driven with the plugin, also counted when the bus has its own odometer
+    pub total_m: f64,
+    pub trips_m: [f64; 2],
+    /// the bus odometer of the last update, when `Odometer` is mapped
+    odometer_m: Option<f64>,
+    last_update: Option<Instant>,
+    changed: bool,
+}
+
+impl Distance {
+    pub const fn new() -> Self {
+        Distance {
+            total_m: 0.0,
+            trips_m: [0.0; 2],
+            odometer_m: None,
+            last_update: None,
+            changed: false,
+        }
+    }
+
+    /// Adds the distance since the last update.
+    ///
+    /// With `odometer_km` the difference of the fractional odometer is used, otherwise `speed_kmh`
+    /// is integrated over the time since the last update.
+    pub fn update(&mut self, odometer_km: Option<f32>, speed_kmh: f32, now: Instant) {
+        let step = match odometer_km {
+            Some(km) => {
+                let odometer_m = km as f64 * 1000.0;
+                let step = self.odometer_m.map_or(0.0, |last| odometer_m - last);
+                self.odometer_m = Some(odometer_m);
+                if (0.0..=MAX_ODOMETER_STEP_M).contains(&step) {
+                    step
+                } else {
+                    0.0
+                }
+            }
+            None => {
+                self.odometer_m = None;
+                let dt = self.last_update.map_or(Duration::ZERO, |last| {
+                    now.duration_since(last).min(MAX_STEP)
+                });
+                speed_kmh.abs() as f64 / 3.6 * dt.as_secs_f64()
+            }
+        };
+        self.last_update = Some(now);
+
+        if step > 0.0 {
+            self.total_m += step;
+            for trip in &mut self.trips_m {
+                *trip += step;
+            }
+            self.changed = true;
+        }
+    }
+
+    /// Metres sent as the KOMSI odometer, the bus odometer if there is one.
+    pub fn odometer_m(&self) -> f64 {
+        self.odometer_m.unwrap_or(self.total_m)
+    }
+
+    pub fn reset_trip(&mut self, trip: usize) {
+        if let Some(t) = self.trips_m.get_mut(trip) {
+            *t = 0.0;
+            self.changed = true;
+        }
+    }
+
+    /// Reads the totals written by [`Distance::save`], a missing file starts at zero.
+    pub fn load(path: &str) -> std::io::Result<Distance> {
+        let mut distance = Distance::new();
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(distance),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let Ok(value) = value.trim().parse::<f64>() else {
+                continue;
+            };
+            match key.trim() {
+                "total" => distance.total_m = value,
+                "trip1" => distance.trips_m[0] = value,
+                "trip2" => distance.trips_m[1] = value,
+                _ => {}
+            }
+        }
+        Ok(distance)
+    }
+
+    /// Writes the totals if they changed since the last save.
+    pub fn save(&mut self, path: &str) -> std::io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let content = format!(
+            "; written by omsi2komsi, distances in metres\ntotal = {:.1}\ntrip1 = {:.1}\ntrip2 = {:.1}\n",
+            self.total_m, self.trips_m[0], self.trips_m[1]
+        );
+        // written next to it first, a crash while writing must not lose the odometer
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, path)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 0.01, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn odometer_steps() {
+        let mut d = Distance::new();
+        let now = Instant::now();
+        // the first value only sets the start
+        d.update(Some(1000.0), 0.0, now);
+        assert_eq!(d.total_m, 0.0);
+        assert_near(d.odometer_m(), 1_000_000.0);
+        d.update(Some(1000.25), 0.0, now);
+        assert_near(d.total_m, 250.0);
+        assert_near(d.trips_m[0], 250.0);
+        assert_near(d.trips_m[1], 250.0);
+        // a bus change jumps, a reset goes backwards, both are not driven
+        d.update(Some(1200.0), 0.0, now);
+        d.update(Some(3.0), 0.0, now);
+        assert_near(d.total_m, 250.0);
+        d.update(Some(3.1), 0.0, now);
+        assert_near(d.total_m, 350.0);
+        assert_near(d.odometer_m(), 3100.0);
+    }
+
+    #[test]
+    fn speed_integration() {
+        let mut d = Distance::new();
+        let start = Instant::now();
+        d.update(None, 36.0, start);
+        assert_eq!(d.total_m, 0.0);
+        // 36 km/h are 10 m/s, reversing counts as well
+        d.update(None, -36.0, start + Duration::from_millis(500));
+        assert_near(d.total_m, 5.0);
+        // a paused game counts at most MAX_STEP
+        d.update(None, 36.0, start + Duration::from_secs(60));
+        assert_near(d.total_m, 15.0);
+        assert_near(d.odometer_m(), 15.0);
+    }
+
+    #[test]
+    fn reset_trip() {
+        let mut d = Distance::new();
+        let now = Instant::now();
+        d.update(Some(0.0), 0.0, now);
+        d.update(Some(0.1), 0.0, now);
+        d.reset_trip(1);
+        d.reset_trip(5);
+        assert_near(d.trips_m[0], 100.0);
+        assert_eq!(d.trips_m[1], 0.0);
+        assert_near(d.total_m, 100.0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path =
+            std::env::temp_dir().join(format!("omsi2komsi_test_{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        assert_eq!(Distance::load(path).unwrap().total_m, 0.0);
+
+        let mut d = Distance::new();
+        // nothing changed, nothing written
+        d.save(path).unwrap();
+        assert!(!std::path::Path::new(path).exists());
+
+        let now = Instant::now();
+        d.update(Some(0.0), 0.0, now);
+        d.update(Some(0.4), 0.0, now);
+        d.save(path).unwrap();
+        d.reset_trip(0);
+        // replaces the existing file
+        d.save(path).unwrap();
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        let loaded = Distance::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_near(loaded.total_m, 400.0);
+        assert_eq!(loaded.trips_m[0], 0.0);
+        assert_near(loaded.trips_m[1], 400.0);
+    }
+}
@@ -0,0 +1,163 @@
+//! The vehicle state and the `[stringvarlist]` values as named fields, for outputs that send single
+//! fields instead of KOMSI lines (WebSocket, MQTT).
+
+use komsi::vehicle::VehicleState;
+
+use crate::{STRING_VAR_NAMES, STRING_VAR_VALUES};
+
+/// Names of the vehicle fields, as in `VehicleState`.
+pub const VEHICLE_FIELDS: &[&str] = &[
+    "ignition",
+    "engine",
+    "doors",
+    "speed",
+    "maxspeed",
+    "fuel",
+    "indicator",
+    "fixing_brake",
+    "lights_warning",
+    "lights_main",
+    "lights_front_door",
+    "lights_second_door",
+    "lights_third_door",
+    "lights_fourth_door",
+    "lights_stop_request",
+    "lights_stop_brake",
+    "lights_high_beam",
+    "battery_light",
+    "gear_selector",
+    "door_clearance",
+    "datetime",
+    "total_distance",
+    "total_distance_km",
+];
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Bool(bool),
+    Number(u64),
+    Text(String),
+}
+
+impl Value {
+    pub fn json(&self) -> String {
+        match self {
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::Text(s) => json_string(s),
+        }
+    }
+
+    /// The value as plain text, booleans as `0` and `1` like in KOMSI.
+    pub fn text(&self) -> String {
+        match self {
+            Value::Bool(b) => (*b as u8).to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::Text(s) => s.clone(),
+        }
+    }
+}
+
+/// A set of fields, either all of them or the changed ones.
+#[derive(Clone, Default)]
+pub struct Fields {
+    /// in the order of [`VEHICLE_FIELDS`]
+    pub vehicle: Vec<(&'static str, Value)>,
+    pub strings: Vec<(String, String)>,
+}
+
+impl Fields {
+    /// Reads `state` and the current string variables.
+    pub fn read(state: &VehicleState) -> Fields {
+        let dt = &state.datetime;
+        let values = [
+            Value::Bool(state.ignition),
+            Value::Bool(state.engine),
+            Value::Bool(state.doors),
+            Value::Number(state.speed as u64),
+            Value::Number(state.maxspeed as u64),
+            Value::Number(state.fuel as u64),
+            Value::Number(state.indicator as u64),
+            Value::Bool(state.fixing_brake),
+            Value::Bool(state.lights_warning),
+            Value::Bool(state.lights_main),
+            Value::Bool(state.lights_front_door),
+            Value::Bool(state.lights_second_door),
+            Value::Bool(state.lights_third_door),
+            Value::Bool(state.lights_fourth_door),
+            Value::Bool(state.lights_stop_request),
+            Value::Bool(state.lights_stop_brake),
+            Value::Bool(state.lights_high_beam),
+            Value::Bool(state.battery_light),
+            Value::Number(state.gear_selector as u64),
+            Value::Bool(state.door_clearance),
+            Value::Text(format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                dt.year, dt.month, dt.day, dt.hour, dt.min, dt.sec
+            )),
+            Value::Number(state.total_distance),
+            Value::Number(state.total_distance_km),
+        ];
+        let vehicle = VEHICLE_FIELDS.iter().copied().zip(values).collect();
+
+        let strings = match (STRING_VAR_NAMES.read(), STRING_VAR_VALUES.read()) {
+            (Ok(names), Ok(values)) => names.iter().cloned().zip(values.iter().cloned()).collect(),
+            _ => Vec::new(),
+        };
+        Fields { vehicle, strings }
+    }
+
+    /// Returns the fields that differ from `old`, all of them when `old` is empty.
+    pub fn changed_since(&self, old: &Fields) -> Fields {
+        let vehicle = if old.vehicle.is_empty() {
+            self.vehicle.clone()
+        } else {
+            self.vehicle
+                .iter()
+                .zip(&old.vehicle)
+                .filter(|(new, old)| new != old)
+                .map(|(new, _)| new.clone())
+                .collect()
+        };
+        // after a profile switch the names differ, then all strings are new
+        let strings = if self.strings.len() == old.strings.len()
+            && self
+                .strings
+                .iter()
+                .zip(&old.strings)
+                .all(|(n, o)| n.0 == o.0)
+        {
+            self.strings
+                .iter()
+                .zip(&old.strings)
+                .filter(|(new, old)| new.1 != old.1)
+                .map(|(new, _)| new.clone())
+                .collect()
+        } else {
+            self.strings.clone()
+        };
+        Fields { vehicle, strings }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vehicle.is_empty() && self.strings.is_empty()
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -1,25 +1,44 @@
 #![allow(dead_code)]
-#[cfg(not(target_arch = "x86"))]
+#[cfg(all(windows, not(target_arch = "x86")))]
 compile_error!("This plugin must be compiled for x86 (32-bit) to be compatible with OMSI!");
 
-use configparser::ini::Ini;
-use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use libc::c_char;
 use libc::c_float;
-use std::ffi::OsString;
 use std::fs::OpenOptions;
-use std::io::Write;
-use std::os::windows::ffi::OsStringExt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::slice;
-use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
 use std::sync::{Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use atomic_float::AtomicF32;
 
 use komsi::komsi::KomsiCommand;
 use komsi::vehicle::{VehicleLogger, VehicleState};
+use omsi_opl::expr::{self, Expr};
+use omsi_opl::{
+    Diagnostic, EngineMode, Entry, HazardMode, IndicatorMode, KOMSI_LETTERS, Opl, PortSettings,
+    RESYNC, Rounding, SpeedUnit, TRIP_RESETS, TextEncoding, parse_hotkey,
+};
+
+mod distance;
+mod fields;
+mod mqtt;
+mod record;
+mod serial;
+mod tcp;
+mod udp;
+mod web;
+use distance::Distance;
+use mqtt::{MqttOutput, MqttSettings};
+pub use record::replay;
+use serial::{PortConfig, PortWorker};
+use tcp::TcpOutput;
+use udp::UdpOutput;
+use web::WebOutput;
 
 #[allow(non_camel_case_types)]
 pub type uintptr_t = usize;
@@ -32,24 +51,35 @@ impl VehicleLogger for GuiLogger {
     }
 }
 
-const SHARED_ARRAY_SIZE: usize = 30;
+// OMSI passes the variable index as u16, later entries of a list can never be reached
+const MAX_VARIABLES: usize = u16::MAX as usize + 1;
 
 use std::sync::RwLock;
 
 static VAR_NAMES: RwLock<Vec<String>> = RwLock::new(Vec::new());
 static STRING_VAR_NAMES: RwLock<Vec<String>> = RwLock::new(Vec::new());
 static STRING_VAR_VALUES: RwLock<Vec<String>> = RwLock::new(Vec::new());
-static HOTKEY: OnceLock<u32> = OnceLock::new();
+static HOTKEY: AtomicU32 = AtomicU32::new(0x79);
 static LOG_MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
 static WINDOW_VISIBLE: AtomicBool = AtomicBool::new(false);
 static SERIAL_PORT_ENABLED: AtomicBool = AtomicBool::new(false);
 static DEBUG_MODE: AtomicBool = AtomicBool::new(false);
 static SYSTEM_VAR_COUNT: AtomicUsize = AtomicUsize::new(0);
+static TRIGGER_NAMES: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static TRIGGER_HOTKEYS: RwLock<Vec<(u32, InputTarget)>> = RwLock::new(Vec::new());
+static DISTANCE: Mutex<Distance> = Mutex::new(Distance::new());
+static RESYNC_REQUESTED: AtomicBool = AtomicBool::new(false);
+// set by the replay, which neither records nor counts the distance of a real drive
+static REPLAY: AtomicBool = AtomicBool::new(false);
 
-static SERIAL_PORTS: Mutex<Vec<Option<Box<dyn serialport::SerialPort>>>> = Mutex::new(Vec::new());
+static INPUT_MAPPINGS: RwLock<Vec<(String, InputTarget)>> = RwLock::new(Vec::new());
 
+/// Writes a message of a C caller to the log window.
+///
+/// # Safety
+/// `msg` must be null or point to a nul-terminated string.
 #[unsafe(no_mangle)]
-pub extern "C" fn log_message_extern(msg: *const c_char) {
+pub unsafe extern "C" fn log_message_extern(msg: *const c_char) {
     if msg.is_null() {
         return;
     }
@@ -62,6 +92,10 @@ pub extern "C" fn log_message_extern(msg: *const c_char) {
 }
 
 fn log_message(msg: String) {
+    // without the log window, e.g. in the replay tool on Linux
+    #[cfg(not(windows))]
+    eprintln!("{}", msg);
+
     if let Ok(mut messages) = LOG_MESSAGES.lock() {
         messages.push(msg.clone());
         if messages.len() > 20 {
@@ -109,6 +143,7 @@ struct OmsiData {
     month: AtomicF32,
     year: AtomicF32,
     odometer: AtomicF32,
+    rpm: AtomicF32,
 }
 
 static OMSI_DATA: OmsiData = OmsiData {
@@ -135,8 +170,41 @@ static OMSI_DATA: OmsiData = OmsiData {
     month: AtomicF32::new(0.0),
     year: AtomicF32::new(0.0),
     odometer: AtomicF32::new(0.0),
+    rpm: AtomicF32::new(0.0),
 };
 
+impl OmsiData {
+    fn field(&self, field: OmsiDataField) -> Option<&AtomicF32> {
+        match field {
+            OmsiDataField::Ignition => Some(&self.ignition),
+            OmsiDataField::Engine => Some(&self.engine),
+            OmsiDataField::BatteryLight => Some(&self.battery),
+            OmsiDataField::Speed => Some(&self.speed),
+            OmsiDataField::FrontDoor => Some(&self.front_door),
+            OmsiDataField::SecondDoor => Some(&self.second_door),
+            OmsiDataField::ThirdDoor => Some(&self.third_door),
+            OmsiDataField::StopRequest => Some(&self.stop_request),
+            OmsiDataField::MainLights => Some(&self.light_main),
+            OmsiDataField::HighBeam => Some(&self.lights_high_beam),
+            OmsiDataField::FixingBrake => Some(&self.fixing_brake),
+            OmsiDataField::IndicatorLeft => Some(&self.indicator_left),
+            OmsiDataField::IndicatorRight => Some(&self.indicator_right),
+            OmsiDataField::WarningLights => Some(&self.warning_lights),
+            OmsiDataField::Fuel => Some(&self.fuel),
+            OmsiDataField::StopBrake => Some(&self.stop_brake),
+            OmsiDataField::PassengerDoorsOpen => Some(&self.passenger_doors_open),
+            OmsiDataField::DoorClearance => Some(&self.door_clearance),
+            OmsiDataField::Time => Some(&self.time),
+            OmsiDataField::Day => Some(&self.day),
+            OmsiDataField::Month => Some(&self.month),
+            OmsiDataField::Year => Some(&self.year),
+            OmsiDataField::Odometer => Some(&self.odometer),
+            OmsiDataField::Rpm => Some(&self.rpm),
+            OmsiDataField::None => None,
+        }
+    }
+}
+
 #[repr(usize)]
 #[derive(Clone, Copy, PartialEq, Debug)]
 enum OmsiDataField {
@@ -164,44 +232,607 @@ enum OmsiDataField {
     DoorClearance,
     Speed,
     Fuel,
+    Rpm,
+}
+
+impl OmsiDataField {
+    /// Every field except `None`, a new field has to be added here as well.
+    const ALL: [OmsiDataField; 24] = [
+        OmsiDataField::Time,
+        OmsiDataField::Day,
+        OmsiDataField::Month,
+        OmsiDataField::Year,
+        OmsiDataField::Odometer,
+        OmsiDataField::Ignition,
+        OmsiDataField::Engine,
+        OmsiDataField::PassengerDoorsOpen,
+        OmsiDataField::IndicatorLeft,
+        OmsiDataField::IndicatorRight,
+        OmsiDataField::FixingBrake,
+        OmsiDataField::WarningLights,
+        OmsiDataField::MainLights,
+        OmsiDataField::FrontDoor,
+        OmsiDataField::SecondDoor,
+        OmsiDataField::ThirdDoor,
+        OmsiDataField::StopRequest,
+        OmsiDataField::StopBrake,
+        OmsiDataField::HighBeam,
+        OmsiDataField::BatteryLight,
+        OmsiDataField::DoorClearance,
+        OmsiDataField::Speed,
+        OmsiDataField::Fuel,
+        OmsiDataField::Rpm,
+    ];
 }
 
 impl From<usize> for OmsiDataField {
     fn from(v: usize) -> Self {
-        match v {
-            x if x == OmsiDataField::Ignition as usize => OmsiDataField::Ignition,
-            x if x == OmsiDataField::Engine as usize => OmsiDataField::Engine,
-            x if x == OmsiDataField::BatteryLight as usize => OmsiDataField::BatteryLight,
-            x if x == OmsiDataField::Speed as usize => OmsiDataField::Speed,
-            x if x == OmsiDataField::FrontDoor as usize => OmsiDataField::FrontDoor,
-            x if x == OmsiDataField::SecondDoor as usize => OmsiDataField::SecondDoor,
-            x if x == OmsiDataField::ThirdDoor as usize => OmsiDataField::ThirdDoor,
-            x if x == OmsiDataField::StopRequest as usize => OmsiDataField::StopRequest,
-            x if x == OmsiDataField::MainLights as usize => OmsiDataField::MainLights,
-            x if x == OmsiDataField::HighBeam as usize => OmsiDataField::HighBeam,
-            x if x == OmsiDataField::FixingBrake as usize => OmsiDataField::FixingBrake,
-            x if x == OmsiDataField::IndicatorLeft as usize => OmsiDataField::IndicatorLeft,
-            x if x == OmsiDataField::IndicatorRight as usize => OmsiDataField::IndicatorRight,
-            x if x == OmsiDataField::WarningLights as usize => OmsiDataField::WarningLights,
-            x if x == OmsiDataField::Fuel as usize => OmsiDataField::Fuel,
-            x if x == OmsiDataField::StopBrake as usize => OmsiDataField::StopBrake,
-            x if x == OmsiDataField::PassengerDoorsOpen as usize => {
-                OmsiDataField::PassengerDoorsOpen
+        OmsiDataField::ALL
+            .into_iter()
+            .find(|field| *field as usize == v)
+            .unwrap_or(OmsiDataField::None)
+    }
+}
+
+/// Per-variable and per-trigger storage, sized from the config in `PluginStart`.
+///
+/// The lists cannot change while OMSI is running, so the storage is allocated once and the OMSI
+/// callbacks reach it without taking a lock.
+struct Storage {
+    /// `OmsiDataField` of every system and user variable
+    data_mapping: Box<[AtomicUsize]>,
+    /// raw value of every variable, used by the expression mappings
+    var_values: Box<[AtomicF32]>,
+    pending_writes: Box<[PendingWrite]>,
+    /// triggers requested by the hardware or a hotkey, fired on the next AccessTrigger callback
+    pending_triggers: Box<[AtomicBool]>,
+    /// triggers we have set active and must reset on their next callback
+    active_triggers: Box<[AtomicBool]>,
+}
+
+impl Storage {
+    fn new(var_count: usize, trigger_count: usize) -> Self {
+        Storage {
+            data_mapping: (0..var_count)
+                .map(|_| AtomicUsize::new(OmsiDataField::None as usize))
+                .collect(),
+            var_values: (0..var_count).map(|_| AtomicF32::new(0.0)).collect(),
+            pending_writes: (0..var_count)
+                .map(|_| PendingWrite {
+                    value: AtomicF32::new(0.0),
+                    pending: AtomicBool::new(false),
+                })
+                .collect(),
+            pending_triggers: (0..trigger_count).map(|_| AtomicBool::new(false)).collect(),
+            active_triggers: (0..trigger_count).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+}
+
+static STORAGE: OnceLock<Storage> = OnceLock::new();
+// set once OMSI used an index beyond the configured lists, so the warning is logged only once
+static INDEX_WARNING_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Logs once that OMSI called with an index the config does not know about.
+fn warn_index_out_of_range(kind: &str, index: usize) {
+    if !INDEX_WARNING_LOGGED.swap(true, Relaxed) {
+        log_message(format!(
+            "OMSI accessed {} index {} which is beyond the lists in the config, check the count lines",
+            kind, index
+        ));
+    }
+}
+
+// mappings that are evaluated in the send loop instead of the OMSI callback
+static EXPR_MAPPINGS: RwLock<Vec<(OmsiDataField, Expr)>> = RwLock::new(Vec::new());
+
+/// Evaluates the expression mappings and stores the results in `OMSI_DATA`.
+fn apply_expression_mappings() {
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    if let Ok(mappings) = EXPR_MAPPINGS.read() {
+        let value = |idx: usize| storage.var_values.get(idx).map_or(0.0, |v| v.load(Relaxed));
+        for (field, expr) in mappings.iter() {
+            if let Some(data) = OMSI_DATA.field(*field) {
+                data.store(expr.eval(&value), Relaxed);
             }
-            x if x == OmsiDataField::DoorClearance as usize => OmsiDataField::DoorClearance,
-            x if x == OmsiDataField::Time as usize => OmsiDataField::Time,
-            x if x == OmsiDataField::Day as usize => OmsiDataField::Day,
-            x if x == OmsiDataField::Month as usize => OmsiDataField::Month,
-            x if x == OmsiDataField::Year as usize => OmsiDataField::Year,
-            x if x == OmsiDataField::Odometer as usize => OmsiDataField::Odometer,
+        }
+    }
+}
+
+impl OmsiDataField {
+    fn from_name(name: &str) -> OmsiDataField {
+        match name {
+            "ignition" => OmsiDataField::Ignition,
+            "batterylight" => OmsiDataField::BatteryLight,
+            "engine" => OmsiDataField::Engine, // used with enginemode = engine
+            "speed" => OmsiDataField::Speed,
+            "frontdoor" => OmsiDataField::FrontDoor,
+            "seconddoor" => OmsiDataField::SecondDoor,
+            "thirddoor" => OmsiDataField::ThirdDoor,
+            "stoprequest" => OmsiDataField::StopRequest,
+            "mainlights" => OmsiDataField::MainLights,
+            "highbeam" => OmsiDataField::HighBeam,
+            "fixingbrake" => OmsiDataField::FixingBrake,
+            "indicatorleft" => OmsiDataField::IndicatorLeft,
+            "indicatorright" => OmsiDataField::IndicatorRight,
+            "warninglights" => OmsiDataField::WarningLights, // used with hazardmode = switch or both
+            "fuel" => OmsiDataField::Fuel,
+            "stopbrake" => OmsiDataField::StopBrake,
+            "doorclearance" => OmsiDataField::DoorClearance,
+            "passengerdoorsopen" => OmsiDataField::PassengerDoorsOpen,
+            "time" => OmsiDataField::Time,
+            "day" => OmsiDataField::Day,
+            "month" => OmsiDataField::Month,
+            "year" => OmsiDataField::Year,
+            "odometer" => OmsiDataField::Odometer,
+            "rpm" => OmsiDataField::Rpm, // used with enginemode = rpm
             _ => OmsiDataField::None,
         }
     }
 }
 
-static DATA_MAPPING: [AtomicUsize; SHARED_ARRAY_SIZE] =
-    [const { AtomicUsize::new(OmsiDataField::None as usize) }; SHARED_ARRAY_SIZE];
+/// Whether a lowercase name is a field of `[datamappings]`, for `Opl::validate`.
+fn is_data_field(name: &str) -> bool {
+    OmsiDataField::from_name(name) != OmsiDataField::None
+}
+
+/// A set of data mappings for one bus model.
+///
+/// Profiles are defined in `[profile:name]` sections and selected at runtime by the value of a
+/// string variable, the default profile is built from `[datamappings]`.
+struct Profile {
+    name: String,
+    /// index into the string variable list and the pattern its value must match
+    selector: Option<(usize, String)>,
+    engineonvalue: u8,
+    /// values above the threshold switch lamps and states on
+    threshold: f32,
+    engine_mode: EngineMode,
+    /// the engine runs above this RPM with `EngineMode::Rpm`
+    engine_rpm: f32,
+    mappings: Vec<(usize, OmsiDataField)>,
+    expr_mappings: Vec<(OmsiDataField, Expr)>,
+}
+
+impl Profile {
+    fn new(name: &str, settings: &omsi_opl::Settings) -> Self {
+        Profile {
+            name: name.to_string(),
+            selector: None,
+            engineonvalue: settings.engine_on_value,
+            threshold: settings.threshold,
+            engine_mode: settings.engine_mode,
+            engine_rpm: settings.engine_rpm,
+            mappings: Vec::new(),
+            expr_mappings: Vec::new(),
+        }
+    }
+
+    /// Builds a profile from its `[profile:name]` section, missing settings are taken from `default`.
+    fn from_section(
+        section: &omsi_opl::Profile,
+        default: &Profile,
+        var_names: &[String],
+        string_var_names: &[String],
+    ) -> Self {
+        let mut profile = Profile {
+            name: section.name.clone(),
+            selector: None,
+            engineonvalue: section.engine_on_value.unwrap_or(default.engineonvalue),
+            threshold: section.threshold.unwrap_or(default.threshold),
+            engine_mode: section.engine_mode.unwrap_or(default.engine_mode),
+            engine_rpm: section.engine_rpm.unwrap_or(default.engine_rpm),
+            mappings: Vec::new(),
+            expr_mappings: Vec::new(),
+        };
+
+        for entry in &section.mappings {
+            profile.add_data_mapping(entry, var_names);
+        }
+
+        // an unknown or missing stringvar is reported by the config validation
+        if let Some(string_var) = &section.string_var {
+            let var = string_var.value.to_lowercase();
+            if let Some(idx) = string_var_names.iter().position(|n| *n == var) {
+                profile.selector = Some((idx, section.pattern.clone()));
+            }
+        }
+
+        profile
+    }
+
+    /// Whether `value` switches `field` on. Expressions are on when not zero, like the results of
+    /// their comparisons, so the threshold does not apply to them a second time.
+    fn is_on(&self, field: OmsiDataField, value: f32) -> bool {
+        if self.expr_mappings.iter().any(|(f, _)| *f == field) {
+            value != 0.0
+        } else {
+            value > self.threshold
+        }
+    }
+
+    fn maps(&self, field: OmsiDataField) -> bool {
+        self.mappings.iter().any(|(_, f)| *f == field)
+            || self.expr_mappings.iter().any(|(f, _)| *f == field)
+    }
+
+    /// Logs when the engine or hazard mode needs a field this profile does not map.
+    fn check_modes(&self, hazard_mode: HazardMode) {
+        let engine_field = match self.engine_mode {
+            EngineMode::Battery => None,
+            EngineMode::Engine => Some(OmsiDataField::Engine),
+            EngineMode::Rpm => Some(OmsiDataField::Rpm),
+        };
+        if let Some(field) = engine_field
+            && !self.maps(field)
+        {
+            log_message(format!(
+                "Profile '{}': enginemode {:?} needs a mapping for {:?}",
+                self.name, self.engine_mode, field
+            ));
+        }
+        if hazard_mode == HazardMode::Switch && !self.maps(OmsiDataField::WarningLights) {
+            log_message(format!(
+                "Profile '{}': hazardmode Switch needs a mapping for WarningLights",
+                self.name
+            ));
+        }
+    }
+
+    /// Parses one `Field=source` mapping line, `source` is a variable list or an expression.
+    ///
+    /// Unknown fields and variables are skipped here, the config validation reports them.
+    fn add_data_mapping(&mut self, entry: &Entry, var_names: &[String]) {
+        let target = entry.key.to_lowercase();
+        let source = entry.value.to_lowercase();
+
+        let field = OmsiDataField::from_name(&target);
+        if field == OmsiDataField::None {
+            return;
+        }
+
+        let resolve = |name: &str| var_names.iter().position(|n| n == name);
+
+        let is_plain = source.split(',').all(|p| expr::is_identifier(p.trim()));
+
+        // invalid expressions are reported with their line number by the config validation
+        if !is_plain {
+            if let Ok(e) = Expr::parse(&source, &resolve) {
+                log_message(format!("Mapping expression '{}' to {:?}", source, field));
+                self.expr_mappings.push((field, e));
+            }
+            return;
+        }
+
+        for source_part in source.split(',') {
+            let source_part = source_part.trim();
+            if let Some(idx) = resolve(source_part) {
+                log_message(format!(
+                    "Mapping variable '{}' (index {}) to {:?}",
+                    source_part, idx, field
+                ));
+                self.mappings.push((idx, field));
+            }
+        }
+    }
+
+    fn matches(&self, string_var_values: &[String]) -> bool {
+        match &self.selector {
+            Some((idx, pattern)) => string_var_values
+                .get(*idx)
+                .is_some_and(|v| !v.is_empty() && wildcard_match(pattern, v)),
+            None => false,
+        }
+    }
+
+    /// Makes this profile the one used by the OMSI callbacks.
+    fn activate(&self) {
+        if let Some(storage) = STORAGE.get() {
+            for mapping in storage.data_mapping.iter() {
+                mapping.store(OmsiDataField::None as usize, Relaxed);
+            }
+            for (idx, field) in &self.mappings {
+                if let Some(mapping) = storage.data_mapping.get(*idx) {
+                    mapping.store(*field as usize, Relaxed);
+                }
+            }
+        }
+
+        // values of the previous profile must not leak into the new one
+        for field in OmsiDataField::ALL {
+            if let Some(data) = OMSI_DATA.field(field) {
+                data.store(0.0, Relaxed);
+            }
+        }
+
+        if let Ok(mut expr_mappings) = EXPR_MAPPINGS.write() {
+            *expr_mappings = self.expr_mappings.clone();
+        }
+    }
+}
+
+/// Returns the index of the first profile matching the current string variables, or the default profile.
+fn select_profile(profiles: &[Profile]) -> usize {
+    match STRING_VAR_VALUES.read() {
+        Ok(values) => profiles
+            .iter()
+            .position(|p| p.matches(&values))
+            .unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// Case-insensitive match where `*` stands for any number of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let first = parts[0];
+    let last = parts[parts.len() - 1];
+    if !text.starts_with(first) || text.len() < first.len() + last.len() {
+        return false;
+    }
+
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    text.ends_with(last)
+}
+
+// values received from the hardware, written back to OMSI on the next callback
+struct PendingWrite {
+    value: AtomicF32,
+    pending: AtomicBool,
+}
+
+/// Target of an `[inputmappings]` entry.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum InputTarget {
+    /// index into the combined system and user variable list
+    Variable(usize),
+    /// index into the `[triggers]` list, fired when the input value is not zero
+    Trigger(usize),
+    /// trip counter, reset when the input value is not zero
+    TripReset(usize),
+    /// resends the complete state when the input value is not zero
+    Resync,
+}
+
+/// Returns the target for the names the plugin handles itself, `tripreset1`, `tripreset2` and `resync`.
+fn plugin_action(name: &str) -> Option<InputTarget> {
+    if name == RESYNC {
+        return Some(InputTarget::Resync);
+    }
+    TRIP_RESETS
+        .iter()
+        .position(|r| *r == name)
+        .map(InputTarget::TripReset)
+}
+
+/// Requests a trigger to be fired on its next AccessTrigger callback.
+fn fire_trigger(idx: usize) {
+    if let Some(pending) = STORAGE.get().and_then(|s| s.pending_triggers.get(idx)) {
+        pending.store(true, Relaxed);
+    }
+}
+
+fn reset_trip(trip: usize) {
+    if let Ok(mut distance) = DISTANCE.lock() {
+        distance.reset_trip(trip);
+    }
+    log_message(format!("Trip {} reset", trip + 1));
+}
+
+// km/h below zero before the bus counts as reversing, so the flag does not flicker at standstill
+const REVERSE_SPEED: f32 = 0.5;
+
+// maximum length of an incoming KOMSI line before we discard it
+const INPUT_LINE_MAX: usize = 256;
+
+/// Returns the input mapping name and the value of a KOMSI command received from the hardware.
+fn input_command_value(cmd: &KomsiCommand) -> Option<(&'static str, f32)> {
+    let v = match *cmd {
+        KomsiCommand::Ignition(b) => ("ignition", b as u8 as f32),
+        KomsiCommand::Engine(b) => ("engine", b as u8 as f32),
+        KomsiCommand::PassengerDoorsOpen(b) => ("passengerdoorsopen", b as u8 as f32),
+        KomsiCommand::Indicator(v) => ("indicator", v as f32),
+        KomsiCommand::FixingBrake(b) => ("fixingbrake", b as u8 as f32),
+        KomsiCommand::WarningLights(b) => ("warninglights", b as u8 as f32),
+        KomsiCommand::MainLights(b) => ("mainlights", b as u8 as f32),
+        KomsiCommand::FrontDoor(b) => ("frontdoor", b as u8 as f32),
+        KomsiCommand::SecondDoor(b) => ("seconddoor", b as u8 as f32),
+        KomsiCommand::ThirdDoor(b) => ("thirddoor", b as u8 as f32),
+        KomsiCommand::StopRequest(b) => ("stoprequest", b as u8 as f32),
+        KomsiCommand::StopBrake(b) => ("stopbrake", b as u8 as f32),
+        KomsiCommand::HighBeam(b) => ("highbeam", b as u8 as f32),
+        KomsiCommand::BatteryLight(b) => ("batterylight", b as u8 as f32),
+        KomsiCommand::DoorClearance(b) => ("doorclearance", b as u8 as f32),
+        KomsiCommand::MaxSpeed(v) => ("maxspeed", v as f32),
+        KomsiCommand::RPM(v) => ("rpm", v as f32),
+        KomsiCommand::Pressure(v) => ("pressure", v as f32),
+        KomsiCommand::Temperature(v) => ("temperature", v as f32),
+        KomsiCommand::Oil(v) => ("oil", v as f32),
+        KomsiCommand::Fuel(v) => ("fuel", v as f32),
+        KomsiCommand::Speed(v) => ("speed", v as f32),
+        KomsiCommand::Water(v) => ("water", v as f32),
+        KomsiCommand::InfoRequest(b) => ("inforequest", b as u8 as f32),
+        _ => return None,
+    };
+    Some(v)
+}
+
+/// KOMSI letters by the field names used in the `fields` of `[port.N]`.
+const KOMSI_FIELDS: &[(&str, u8)] = &[
+    ("ignition", b'A'),
+    ("engine", b'B'),
+    ("passengerdoorsopen", b'C'),
+    ("indicator", b'D'),
+    ("fixingbrake", b'E'),
+    ("warninglights", b'F'),
+    ("mainlights", b'G'),
+    ("frontdoor", b'H'),
+    ("seconddoor", b'I'),
+    ("thirddoor", b'J'),
+    ("stoprequest", b'K'),
+    ("stopbrake", b'L'),
+    ("highbeam", b'M'),
+    ("batterylight", b'N'),
+    ("doorclearance", b'P'),
+    ("odometer", b'o'),
+    ("datetime", b'r'),
+    ("maxspeed", b's'),
+    ("rpm", b't'),
+    ("pressure", b'u'),
+    ("temperature", b'v'),
+    ("oil", b'w'),
+    ("fuel", b'x'),
+    ("speed", b'y'),
+    ("water", b'z'),
+];
+
+/// Returns the KOMSI letter of a field name, a single letter stands for itself (e.g. `trip1command`).
+fn komsi_letter(name: &str) -> Option<u8> {
+    if name.len() == 1 && name.as_bytes()[0].is_ascii_alphabetic() {
+        return Some(name.as_bytes()[0]);
+    }
+    KOMSI_FIELDS
+        .iter()
+        .find(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|(_, letter)| *letter)
+}
+
+/// Keeps the commands of a KOMSI buffer whose letter is in `letters`, line by line.
+fn filter_komsi(buf: &[u8], letters: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for line in buf.split(|&b| b == b'\n') {
+        let len = out.len();
+        let mut start = 0;
+        while start < line.len() {
+            let mut end = start + 1;
+            if line.get(end) == Some(&b'"') {
+                // a text command, the text ends at the next double quote
+                end = line[end + 1..]
+                    .iter()
+                    .position(|&b| b == b'"')
+                    .map_or(line.len(), |p| end + p + 2);
+            } else {
+                while end < line.len() && line[end].is_ascii_digit() {
+                    end += 1;
+                }
+            }
+            if letters.contains(&line[start]) {
+                out.extend_from_slice(&line[start..end]);
+            }
+            start = end;
+        }
+        if out.len() > len {
+            out.extend_from_slice(&KomsiCommand::build_eol());
+        }
+    }
+    out
+}
+
+/// Splits a received KOMSI line like `K1H0` into its commands.
+fn parse_komsi_line(line: &[u8]) -> Vec<KomsiCommand> {
+    let mut cmds = Vec::new();
+    let mut start = 0;
+    while start < line.len() {
+        let mut end = start + 1;
+        while end < line.len() && line[end].is_ascii_digit() {
+            end += 1;
+        }
+        if line[start].is_ascii_alphabetic()
+            && let Ok(cmd) = KomsiCommand::from_parts(line[start] as char, &line[start + 1..end])
+        {
+            cmds.push(cmd);
+        }
+        start = end;
+    }
+    cmds
+}
+
+/// Reads the available bytes from a serial port and queues mapped input values for OMSI.
+fn read_serial_input(
+    port: &mut Box<dyn serialport::SerialPort>,
+    line_buf: &mut Vec<u8>,
+    input_mappings: &[(String, InputTarget)],
+) -> std::io::Result<()> {
+    let available = port.bytes_to_read()? as usize;
+    if available == 0 {
+        return Ok(());
+    }
+
+    let mut chunk = vec![0u8; available];
+    let n = port.read(&mut chunk)?;
+    handle_input(line_buf, &chunk[..n], input_mappings);
+    Ok(())
+}
+
+/// Adds received bytes to `line_buf` and applies the complete lines.
+fn handle_input(line_buf: &mut Vec<u8>, data: &[u8], input_mappings: &[(String, InputTarget)]) {
+    line_buf.extend_from_slice(data);
 
+    while let Some(pos) = line_buf.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = line_buf.drain(..=pos).collect();
+        for cmd in parse_komsi_line(&line[..pos]) {
+            if let Some((name, value)) = input_command_value(&cmd) {
+                apply_input(name, value, input_mappings);
+            }
+        }
+    }
+
+    if line_buf.len() > INPUT_LINE_MAX {
+        line_buf.clear();
+    }
+}
+
+/// Passes an input value to the targets mapped to `name` in `[inputmappings]`.
+fn apply_input(name: &str, value: f32, input_mappings: &[(String, InputTarget)]) {
+    for (input_name, target) in input_mappings {
+        if input_name != name {
+            continue;
+        }
+        if DEBUG_MODE.load(Relaxed) {
+            log_message(format!("Input {} = {} ({:?})", name, value, target));
+        }
+        match *target {
+            InputTarget::Variable(idx) => {
+                if let Some(write) = STORAGE.get().and_then(|s| s.pending_writes.get(idx)) {
+                    write.value.store(value, Relaxed);
+                    write.pending.store(true, Release);
+                }
+            }
+            InputTarget::Trigger(idx) => {
+                if value != 0.0 {
+                    fire_trigger(idx);
+                }
+            }
+            InputTarget::TripReset(trip) => {
+                if value != 0.0 {
+                    reset_trip(trip);
+                }
+            }
+            InputTarget::Resync => {
+                if value != 0.0 {
+                    RESYNC_REQUESTED.store(true, Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
 fn run_gui() {
     use windows::{
         core::*, Win32::Graphics::Gdi::*,
@@ -265,6 +896,7 @@ fn run_gui() {
     }
 }
 
+#[cfg(windows)]
 extern "system" fn wndproc(
     window: windows::Win32::Foundation::HWND,
     message: u32,
@@ -344,68 +976,134 @@ extern "system" fn wndproc(
     }
 }
 
-pub fn get_vehicle_state_from_omsi(engineonvalue: u8) -> VehicleState {
+/// Keeps a blinking signal on for a while after its last on phase.
+#[derive(Default)]
+struct Latch {
+    last_on: Option<Instant>,
+}
+
+impl Latch {
+    fn update(&mut self, on: bool, hold: Duration, now: Instant) -> bool {
+        if on {
+            self.last_on = Some(now);
+            return true;
+        }
+        self.last_on
+            .is_some_and(|last_on| now.duration_since(last_on) < hold)
+    }
+}
+
+/// State of the blinking lamps that has to survive between two rounds of the send loop.
+#[derive(Default)]
+struct BlinkState {
+    left: Latch,
+    right: Latch,
+    hazard: Latch,
+}
+
+fn get_vehicle_state_from_omsi(
+    config: &PluginConfig,
+    profile: &Profile,
+    blink: &mut BlinkState,
+) -> VehicleState {
+    apply_expression_mappings();
+
     let mut s = VehicleState::new();
+    let on = |field: OmsiDataField| {
+        OMSI_DATA
+            .field(field)
+            .is_some_and(|data| profile.is_on(field, data.load(Relaxed)))
+    };
+
+    s.ignition = on(OmsiDataField::Ignition);
 
-    s.ignition = OMSI_DATA.ignition.load(Relaxed) > 0.5;
+    // the distance is also counted while the ignition is off, the bus may still roll
+    let odometer_m = match DISTANCE.lock() {
+        Ok(mut distance) => {
+            let odometer = profile
+                .maps(OmsiDataField::Odometer)
+                .then(|| OMSI_DATA.odometer.load(Relaxed));
+            distance.update(odometer, OMSI_DATA.speed.load(Relaxed), Instant::now());
+            distance.odometer_m()
+        }
+        Err(_) => OMSI_DATA.odometer.load(Relaxed) as f64 * 1000.0,
+    };
 
     if !s.ignition {
         return s;
     }
 
-    let engineval = OMSI_DATA.battery.load(Relaxed) > 0.5;
-    s.battery_light = engineval;
+    s.battery_light = on(OmsiDataField::BatteryLight);
 
-    // we use the value of the battery light for engine on/off state
-    // we do not check OMSI_DATA.engine
-    // if engineval == engineonvalue {
-    s.engine = engineval;
-    //    }
+    s.engine = match profile.engine_mode {
+        // engineonvalue = 0 for buses whose battery light goes out when the engine runs
+        EngineMode::Battery => s.battery_light as u8 == profile.engineonvalue,
+        EngineMode::Engine => {
+            OMSI_DATA.engine.load(Relaxed).round() == profile.engineonvalue as f32
+        }
+        EngineMode::Rpm => OMSI_DATA.rpm.load(Relaxed) > profile.engine_rpm,
+    };
 
-    s.speed = OMSI_DATA.speed.load(Relaxed) as u32;
+    // OMSI reports reversing as a negative speed, the direction is sent as its own flag
+    let kmh = OMSI_DATA.speed.load(Relaxed).abs();
+    let speed = config.speed_unit.from_kmh(kmh) * config.speed_scale;
+    s.speed = config.speed_rounding.apply(speed) as u32;
 
-    s.lights_front_door = OMSI_DATA.front_door.load(Relaxed) > 0.5;
-    s.lights_second_door = OMSI_DATA.second_door.load(Relaxed) > 0.5;
-    s.lights_third_door = OMSI_DATA.third_door.load(Relaxed) > 0.5;
+    s.lights_front_door = on(OmsiDataField::FrontDoor);
+    s.lights_second_door = on(OmsiDataField::SecondDoor);
+    s.lights_third_door = on(OmsiDataField::ThirdDoor);
 
-    s.door_clearance = OMSI_DATA.door_clearance.load(Relaxed) > 0.5;
+    s.door_clearance = on(OmsiDataField::DoorClearance);
 
     // Türschleife nur noch aus OMSI Variable ermitteln
-    s.doors = OMSI_DATA.passenger_doors_open.load(Relaxed) > 0.5;
+    s.doors = on(OmsiDataField::PassengerDoorsOpen);
     // if s.lights_front_door || s.lights_second_door || s.lights_third_door || s.door_clearance {
     //    s.doors = true;
     // }
 
-    s.lights_stop_request = OMSI_DATA.stop_request.load(Relaxed) > 0.5;
+    s.lights_stop_request = on(OmsiDataField::StopRequest);
 
-    s.lights_high_beam = OMSI_DATA.lights_high_beam.load(Relaxed) > 0.5;
-    s.fixing_brake = OMSI_DATA.fixing_brake.load(Relaxed) > 0.5;
+    s.lights_high_beam = on(OmsiDataField::HighBeam);
+    s.fixing_brake = on(OmsiDataField::FixingBrake);
 
-    s.lights_main = OMSI_DATA.light_main.load(Relaxed) > 0.5;
+    s.lights_main = on(OmsiDataField::MainLights);
 
     // if ail > 0 {
     //     // TODO search different OMSI variable, because this one is always "2" when high beam is active
     //    s.lights_main = true;
     // }
 
-    let ind_l = OMSI_DATA.indicator_left.load(Relaxed) > 0.5;
-    let ind_r = OMSI_DATA.indicator_right.load(Relaxed) > 0.5;
-
-    if ind_l == !ind_r {    // links oder rechts, nicht beide
-        s.indicator = 1
-    } else {
-        s.indicator = 0 // TODO ja, hier sollten wir links/rechts unterscheiden, ist aber erstmal egal
-    }
-
-    s.lights_warning = ind_l && ind_r;
+    // with blinkhold the indicators stay on through the off phase and the hardware blinks by itself
+    let now = Instant::now();
+    let ind_l = blink
+        .left
+        .update(on(OmsiDataField::IndicatorLeft), config.blink_hold, now);
+    let ind_r = blink
+        .right
+        .update(on(OmsiDataField::IndicatorRight), config.blink_hold, now);
+
+    s.indicator = match config.indicator_mode {
+        // links oder rechts, nicht beide, für Firmware mit nur einer Blinkerlampe
+        IndicatorMode::Combined => (ind_l != ind_r) as u8,
+        // 1 = links, 2 = rechts, 3 = beide
+        IndicatorMode::Separate => ind_l as u8 | (ind_r as u8) << 1,
+    };
 
-    // we do not check OMSI_DATA.warning_lights
+    // both indicators are off at the same time during the blink cycle, the latch bridges that gap
+    let hazard_indicators = ind_l && ind_r;
+    let hazard_switch = on(OmsiDataField::WarningLights);
+    let hazard = match config.hazard_mode {
+        HazardMode::Indicators => hazard_indicators,
+        HazardMode::Switch => hazard_switch,
+        HazardMode::Both => hazard_indicators || hazard_switch,
+    };
+    s.lights_warning = blink.hazard.update(hazard, config.hazard_latch, now);
 
     // fuel is in percent, so we multiply by 100
     let f = OMSI_DATA.fuel.load(Relaxed);
     s.fuel = (f.abs() * 100.0).round() as u8;
 
-    s.lights_stop_brake = OMSI_DATA.stop_brake.load(Relaxed) > 0.5;
+    s.lights_stop_brake = on(OmsiDataField::StopBrake);
 
     let time_sec = OMSI_DATA.time.load(Relaxed) as u32;
     s.datetime.hour = (time_sec / 3600) as u8;
@@ -416,288 +1114,392 @@ pub fn get_vehicle_state_from_omsi(engineonvalue: u8) -> VehicleState {
     s.datetime.month = OMSI_DATA.month.load(Relaxed) as u8;
     s.datetime.year = OMSI_DATA.year.load(Relaxed) as u16;
 
-    s.total_distance_km = OMSI_DATA.odometer.load(Relaxed) as u64;
-    s.total_distance = s.total_distance_km * 1000;
+    // KOMSI sends the metres whenever the kilometre changes, the hardware counts in between
+    s.total_distance = odometer_m as u64;
+    s.total_distance_km = s.total_distance / 1000;
 
     s
 }
 
-/// This function is called when the plugin is loaded by Omsi 2.
-///
-/// Original C declaration:
-/// ```c
-/// __declspec(dllexport) void __stdcall PluginStart(void* aOwner)
-/// ```
-///
-/// # Safety
-/// This function links our DLL to Omsi 2, thus it cannot be Safe (raw pointers, etc...)
-#[allow(non_snake_case, unused_variables)]
-// #[unsafe(no_mangle)]
-#[unsafe(export_name = "PluginStart")]
-pub unsafe extern "stdcall" fn PluginStart(aOwner: uintptr_t) {
-    // load config
-
-    let mut config_path = ".\\plugins\\omsi2komsi.opl";
-    if !std::path::Path::new(config_path).exists() {
-        config_path = "omsi2komsi.opl";
-    }
-
-    let mut config = Ini::new();
-    let _ = config.load(config_path);
+/// Settings read from omsi2komsi.opl.
+struct PluginConfig {
+    ports: Vec<PortConfig>,
+    /// `host:port` addresses the KOMSI lines are sent to as UDP datagrams
+    udp_targets: Vec<String>,
+    /// address of the TCP server for KOMSI clients
+    tcp_listen: Option<String>,
+    /// address of the HTTP and WebSocket server
+    web_listen: Option<String>,
+    /// directory with the files of the HTTP server
+    web_root: PathBuf,
+    mqtt: Option<MqttSettings>,
+    /// write the values OMSI hands over to a recording
+    record: bool,
+    serial_enabled: bool,
+    indicator_mode: IndicatorMode,
+    hazard_mode: HazardMode,
+    /// how long the hazard lamp stays on after the last on phase
+    hazard_latch: Duration,
+    /// how long an indicator counts as active after its last on phase
+    blink_hold: Duration,
+    /// KOMSI letters the trip counters are sent with
+    trip_commands: [Option<char>; 2],
+    speed_unit: SpeedUnit,
+    speed_scale: f32,
+    speed_rounding: Rounding,
+    /// KOMSI letter the reverse flag is sent with
+    reverse_command: Option<char>,
+    /// `[stringmappings]`, the letter, index into `string_var_names` and maximum length (0 = none)
+    text_commands: Vec<(char, usize, usize)>,
+    text_encoding: TextEncoding,
+    /// time between two complete resends of the state, zero sends only changes
+    resync_interval: Duration,
+    hotkey: u32,
+    /// system variables followed by the user variables, in the order OMSI calls them
+    var_names: Vec<String>,
+    system_var_count: usize,
+    string_var_names: Vec<String>,
+    trigger_names: Vec<String>,
+    profiles: Vec<Profile>,
+    input_mappings: Vec<(String, InputTarget)>,
+    trigger_hotkeys: Vec<(u32, InputTarget)>,
+}
 
-    let baudrate = config
-        .getint("omsi2komsi", "baudrate")
-        .ok()
-        .flatten()
-        .unwrap_or(115200) as u32;
-    let mut portnames = Vec::new();
-    if let Some(p) = config.get("omsi2komsi", "portname") {
-        if !p.is_empty() {
-            portnames.push(p);
+impl PluginConfig {
+    /// Returns the sections that differ from `other` and cannot change while OMSI is running,
+    /// because OMSI has already bound the variable indices.
+    fn bound_sections_changed(&self, other: &PluginConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.var_names[..self.system_var_count] != other.var_names[..other.system_var_count] {
+            changed.push("[systemvarlist]");
         }
-    }
-    for i in 2..=5 {
-        let key = format!("portname{}", i);
-        if let Some(p) = config.get("omsi2komsi", &key) {
-            if !p.is_empty() {
-                portnames.push(p);
-            }
+        if self.var_names[self.system_var_count..] != other.var_names[other.system_var_count..] {
+            changed.push("[varlist]");
         }
+        if self.string_var_names != other.string_var_names {
+            changed.push("[stringvarlist]");
+        }
+        if self.trigger_names != other.trigger_names {
+            changed.push("[triggers]");
+        }
+        changed
     }
+}
 
-    if portnames.is_empty() {
-        portnames.push("com1".to_string());
+/// Returns the path of omsi2komsi.opl, either in the plugins directory or the current directory.
+fn config_path() -> &'static str {
+    let config_path = ".\\plugins\\omsi2komsi.opl";
+    if std::path::Path::new(config_path).exists() {
+        config_path
+    } else {
+        "omsi2komsi.opl"
     }
+}
 
-    let serial_enabled = config
-        .getbool("omsi2komsi", "serialportenabled")
-        .ok()
-        .flatten()
-        .unwrap_or(false);
-    SERIAL_PORT_ENABLED.store(serial_enabled, Relaxed);
-
-    let debug_mode = config
-        .getbool("omsi2komsi", "debug")
-        .ok()
-        .flatten()
-        .unwrap_or(false);
-    DEBUG_MODE.store(debug_mode, Relaxed);
-
-    let engineonvalue = config
-        .getint("omsi2komsi", "engineonvalue")
-        .ok()
-        .flatten()
-        .unwrap_or(1) as u8;
-
-    if let Ok(content) = std::fs::read_to_string(config_path) {
-        log_message(format!("Loading config from {}", config_path));
+/// Returns the path of the state file with the distance counters, next to omsi2komsi.opl.
+fn state_path() -> &'static str {
+    if config_path().starts_with(".\\plugins") {
+        ".\\plugins\\omsi2komsi.state"
+    } else {
+        "omsi2komsi.state"
+    }
+}
 
-        if debug_mode {
-            let version = env!("CARGO_PKG_VERSION");
-            log_message(format!(
-                "--- omsi2komsi v{} started with debug mode enabled ---",
-                version
-            ));
+/// Reads and parses the config file, sets the debug mode right away so the mapping messages get logged.
+fn load_config(config_path: &str) -> PluginConfig {
+    let opl = match Opl::load(config_path) {
+        Ok(opl) => opl,
+        Err(e) => {
+            log_message(format!("Could not read config {}: {}", config_path, e));
+            Opl::default()
         }
+    };
+    DEBUG_MODE.store(opl.settings.debug, Relaxed);
+    log_message(format!("Loading config from {}", config_path));
+
+    // names are matched in lowercase, OMSI does not care about the case
+    let lowercase =
+        |names: &[String]| -> Vec<String> { names.iter().map(|n| n.to_lowercase()).collect() };
+    let var_names = lowercase(&opl.var_names());
+    let string_var_names = lowercase(&opl.stringvarlist.names);
+    let trigger_names = lowercase(&opl.triggers.names);
+
+    // the first profile is the default one from [datamappings]
+    let mut profiles = vec![Profile::new("default", &opl.settings)];
+    for entry in &opl.datamappings {
+        profiles[0].add_data_mapping(entry, &var_names);
+    }
 
-        let mut in_varlist = false;
-        let mut in_systemvarlist = false;
-        let mut in_stringvarlist = false;
-        let mut in_datamappings = false;
-        let mut in_hotkey = false;
-        let mut hotkey_val = 0x79; // Default F10
-        let mut temp_system_var_names: Vec<String> = Vec::new();
-        let mut temp_var_names: Vec<String> = Vec::new();
+    for section in &opl.profiles {
+        log_message(format!("Loading profile '{}'", section.name));
+        let profile = Profile::from_section(section, &profiles[0], &var_names, &string_var_names);
+        profiles.push(profile);
+    }
+    for profile in &profiles {
+        profile.check_modes(opl.settings.hazard_mode);
+    }
 
-        for line in content.lines() {
-            let line = line.trim();
-            if line.is_empty() || line.starts_with(';') {
-                continue;
-            }
-            if line == "[varlist]" {
-                in_varlist = true;
-                in_systemvarlist = false;
-                in_datamappings = false;
-                in_hotkey = false;
-                continue;
-            }
-            if line == "[systemvarlist]" {
-                in_varlist = false;
-                in_systemvarlist = true;
-                in_stringvarlist = false;
-                in_datamappings = false;
-                in_hotkey = false;
-                continue;
-            }
-            if line == "[stringvarlist]" {
-                in_varlist = false;
-                in_systemvarlist = false;
-                in_stringvarlist = true;
-                in_datamappings = false;
-                in_hotkey = false;
-                continue;
-            }
-            if line == "[datamappings]" {
-                in_varlist = false;
-                in_systemvarlist = false;
-                in_stringvarlist = false;
-                in_datamappings = true;
-                in_hotkey = false;
-                continue;
-            }
-            if line == "[hotkey]" {
-                in_varlist = false;
-                in_systemvarlist = false;
-                in_stringvarlist = false;
-                in_datamappings = false;
-                in_hotkey = true;
-                continue;
+    // inputs can only be written to variables and triggers OMSI knows from the lists
+    let mut input_mappings: Vec<(String, InputTarget)> = Vec::new();
+    for entry in &opl.inputmappings {
+        let input = entry.key.to_lowercase();
+        let target = entry.value.to_lowercase();
+        if let Some(action) = plugin_action(&target) {
+            log_message(format!("Mapping input '{}' to '{}'", input, target));
+            input_mappings.push((input, action));
+            continue;
+        }
+        let var_idx = var_names.iter().position(|name| *name == target);
+        let trigger_idx = trigger_names.iter().position(|name| *name == target);
+        match (var_idx, trigger_idx) {
+            (Some(idx), _) => {
+                log_message(format!(
+                    "Mapping input '{}' to variable '{}' (index {})",
+                    input, target, idx
+                ));
+                input_mappings.push((input, InputTarget::Variable(idx)));
             }
-            if line.starts_with('[') {
-                in_varlist = false;
-                in_systemvarlist = false;
-                in_stringvarlist = false;
-                in_datamappings = false;
-                in_hotkey = false;
-                continue;
+            (_, Some(idx)) => {
+                log_message(format!(
+                    "Mapping input '{}' to trigger '{}' (index {})",
+                    input, target, idx
+                ));
+                input_mappings.push((input, InputTarget::Trigger(idx)));
             }
+            _ => {}
+        }
+    }
 
-            if in_systemvarlist {
-                let var_name = line.to_lowercase();
-                // skip if the line is just the count (integer)
-                if temp_system_var_names.is_empty() && var_name.parse::<u32>().is_ok() {
-                    continue;
-                }
-
-                temp_system_var_names.push(var_name);
-            }
-
-            if in_varlist {
-                let var_name = line.to_lowercase();
-                // skip if the line is just the count (integer)
-                if temp_var_names.is_empty() && var_name.parse::<u32>().is_ok() {
-                    continue;
-                }
-
-                temp_var_names.push(var_name);
+    let mut trigger_hotkeys: Vec<(u32, InputTarget)> = Vec::new();
+    for entry in &opl.triggerhotkeys {
+        let trigger_name = entry.key.to_lowercase();
+        if let Some(action) = plugin_action(&trigger_name) {
+            if let Some(h) = parse_hotkey(&entry.value) {
+                log_message(format!("Hotkey {:#x} fires '{}'", h, trigger_name));
+                trigger_hotkeys.push((h, action));
             }
+            continue;
+        }
+        let trigger_idx = trigger_names.iter().position(|name| *name == trigger_name);
+        if let (Some(idx), Some(h)) = (trigger_idx, parse_hotkey(&entry.value)) {
+            log_message(format!(
+                "Hotkey {:#x} fires trigger '{}' (index {})",
+                h, trigger_name, idx
+            ));
+            trigger_hotkeys.push((h, InputTarget::Trigger(idx)));
+        }
+    }
 
-            if in_stringvarlist {
-                let var_name = line.to_lowercase();
-                // skip if the line is just the count (integer)
-                if let Ok(mut string_var_names) = STRING_VAR_NAMES.write() {
-                    if string_var_names.is_empty() && var_name.parse::<u32>().is_ok() {
-                        continue;
-                    }
-                    string_var_names.push(var_name);
+    let mut problems = opl.diagnostics.clone();
+    let mut ports = Vec::new();
+    for settings in opl.serial_ports() {
+        let letters = settings.field_names().map(|names| {
+            let mut letters = Vec::new();
+            for name in names {
+                match komsi_letter(name) {
+                    Some(letter) => letters.push(letter),
+                    None => problems.push(Diagnostic {
+                        line: settings.fields.as_ref().map_or(0, |f| f.line),
+                        message: format!(
+                            "unknown KOMSI field '{}' in [port.{}]",
+                            name, settings.name
+                        ),
+                    }),
                 }
             }
+            letters
+        });
+        ports.push(PortConfig { settings, letters });
+    }
 
-            if in_hotkey {
-                if line.starts_with("0x") {
-                    if let Ok(h) = u32::from_str_radix(&line[2..], 16) {
-                        hotkey_val = h;
-                    }
-                } else if let Ok(h) = line.parse::<u32>() {
-                    hotkey_val = h;
-                }
+    // variables missing from [stringvarlist] and KOMSI letters are reported by validate
+    let text_commands = opl
+        .stringmappings
+        .iter()
+        .filter(|m| !KOMSI_LETTERS.contains(m.letter))
+        .filter_map(|m| {
+            let idx = string_var_names
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(&m.string_var))?;
+            Some((
+                m.letter,
+                idx,
+                m.max_len.unwrap_or(opl.settings.text_max_len),
+            ))
+        })
+        .collect();
+
+    let mqtt = opl.settings.mqtt_broker.clone().map(|broker| {
+        let mut topics = Vec::new();
+        for entry in &opl.mqtttopics {
+            let name = entry.key.to_lowercase();
+            if !fields::VEHICLE_FIELDS.contains(&name.as_str()) && !string_var_names.contains(&name)
+            {
+                problems.push(Diagnostic {
+                    line: entry.line,
+                    message: format!(
+                        "'{}' in [mqtttopics] is neither a field nor in [stringvarlist]",
+                        entry.key
+                    ),
+                });
+            } else if entry.value.contains(['+', '#']) {
+                problems.push(Diagnostic {
+                    line: entry.line,
+                    message: format!("topic '{}' must not contain + or #", entry.value),
+                });
+            } else {
+                topics.push((name, entry.value.clone()));
             }
+        }
+        MqttSettings {
+            broker,
+            client_id: opl.settings.mqtt_client_id.clone(),
+            user: opl.settings.mqtt_user.clone(),
+            password: opl.settings.mqtt_password.clone(),
+            prefix: opl.settings.mqtt_topic.clone(),
+            topics,
+        }
+    });
 
-            if in_datamappings {
-                let parts: Vec<&str> = line.split('=').collect();
-                if parts.len() == 2 {
-                    let target = parts[0].trim().to_lowercase();
-                    let source = parts[1].trim().to_lowercase();
-
-                    let field = match target.as_str() {
-                        "ignition" => OmsiDataField::Ignition,
-                        "batterylight" => OmsiDataField::BatteryLight,
-                        "engine" => OmsiDataField::Engine, // not used at the moment, we calculate it from batterylight
-                        "speed" => OmsiDataField::Speed,
-                        "frontdoor" => OmsiDataField::FrontDoor,
-                        "seconddoor" => OmsiDataField::SecondDoor,
-                        "thirddoor" => OmsiDataField::ThirdDoor,
-                        "stoprequest" => OmsiDataField::StopRequest,
-                        "mainlights" => OmsiDataField::MainLights,
-                        "highbeam" => OmsiDataField::HighBeam,
-                        "fixingbrake" => OmsiDataField::FixingBrake,
-                        "indicatorleft" => OmsiDataField::IndicatorLeft,
-                        "indicatorright" => OmsiDataField::IndicatorRight,
-                        "warninglights" => OmsiDataField::WarningLights, // not used at the moment, wie calculate it from indi_left + indi_right
-                        "fuel" => OmsiDataField::Fuel,
-                        "stopbrake" => OmsiDataField::StopBrake,
-                        "doorclearance" => OmsiDataField::DoorClearance,
-                        "passengerdoorsopen" => OmsiDataField::PassengerDoorsOpen,
-                        "time" => OmsiDataField::Time,
-                        "day" => OmsiDataField::Day,
-                        "month" => OmsiDataField::Month,
-                        "year" => OmsiDataField::Year,
-                        "odometer" => OmsiDataField::Odometer,
-                        _ => OmsiDataField::None,
-                    };
-
-                    if field != OmsiDataField::None {
-                        for source_part in source.split(',') {
-                            let source_part = source_part.trim();
-
-                            // Map source_part to index
-                            let mut found_idx = None;
-                            for (i, name) in temp_system_var_names.iter().enumerate() {
-                                if name == source_part {
-                                    found_idx = Some(i);
-                                    break;
-                                }
-                            }
-                            if found_idx.is_none() {
-                                for (i, name) in temp_var_names.iter().enumerate() {
-                                    if name == source_part {
-                                        found_idx = Some(temp_system_var_names.len() + i);
-                                        break;
-                                    }
-                                }
-                            }
+    // logged last so the problems are still visible in the log window after the mapping messages
+    problems.extend(opl.validate(&is_data_field, MAX_VARIABLES));
+    problems.sort_by_key(|d| d.line);
+    for problem in &problems {
+        log_message(format!("{}: {}", config_path, problem));
+    }
+    if !problems.is_empty() {
+        log_message(format!(
+            "{} problem(s) found in {}, see above",
+            problems.len(),
+            config_path
+        ));
+    }
 
-                            if let Some(idx) = found_idx {
-                                if idx < SHARED_ARRAY_SIZE {
-                                    log_message(format!(
-                                        "Mapping variable '{}' (index {}) to {:?}",
-                                        source_part, idx, field
-                                    ));
-                                    DATA_MAPPING[idx].store(field as usize, Relaxed);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    PluginConfig {
+        ports,
+        udp_targets: opl.settings.udp_targets,
+        tcp_listen: opl.settings.tcp_listen,
+        web_listen: opl.settings.web_listen,
+        // relative to the directory of omsi2komsi.opl, an absolute path replaces it
+        web_root: Path::new(config_path)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(&opl.settings.web_root),
+        mqtt,
+        record: opl.settings.record && !REPLAY.load(Relaxed),
+        serial_enabled: opl.settings.serial_port_enabled,
+        indicator_mode: opl.settings.indicator_mode,
+        hazard_mode: opl.settings.hazard_mode,
+        hazard_latch: Duration::from_millis(opl.settings.hazard_latch_ms),
+        blink_hold: Duration::from_millis(opl.settings.blink_hold_ms),
+        // reserved letters are reported by validate
+        trip_commands: opl
+            .settings
+            .trip_commands
+            .map(|c| c.filter(|c| !KOMSI_LETTERS.contains(*c))),
+        speed_unit: opl.settings.speed_unit,
+        speed_scale: opl.settings.speed_scale,
+        speed_rounding: opl.settings.speed_rounding,
+        reverse_command: opl
+            .settings
+            .reverse_command
+            .filter(|c| !KOMSI_LETTERS.contains(*c)),
+        text_commands,
+        text_encoding: opl.settings.text_encoding,
+        resync_interval: Duration::from_secs(opl.settings.resync_interval_s),
+        hotkey: opl.hotkey,
+        var_names,
+        system_var_count: opl.systemvarlist.len(),
+        string_var_names,
+        trigger_names,
+        profiles,
+        input_mappings,
+        trigger_hotkeys,
+    }
+}
 
-        SYSTEM_VAR_COUNT.store(temp_system_var_names.len(), Relaxed);
-        let mut combined_names = temp_system_var_names;
-        combined_names.extend(temp_var_names);
+/// This function is called when the plugin is loaded by Omsi 2.
+///
+/// Original C declaration:
+/// ```c
+/// __declspec(dllexport) void __stdcall PluginStart(void* aOwner)
+/// ```
+///
+/// # Safety
+/// This function links our DLL to Omsi 2, thus it cannot be Safe (raw pointers, etc...)
+#[allow(non_snake_case, unused_variables)]
+// #[unsafe(no_mangle)]
+#[unsafe(export_name = "PluginStart")]
+pub unsafe extern "system" fn PluginStart(aOwner: uintptr_t) {
+    // load config
+    let config_path = config_path();
+    let mut config = load_config(config_path);
+    let mut config_modified = std::fs::metadata(config_path)
+        .and_then(|m| m.modified())
+        .ok();
 
-        if let Ok(mut var_names) = VAR_NAMES.write() {
-            *var_names = combined_names;
-        }
-        let _ = HOTKEY.set(hotkey_val);
+    if DEBUG_MODE.load(Relaxed) {
+        let version = env!("CARGO_PKG_VERSION");
+        log_message(format!(
+            "--- omsi2komsi v{} started with debug mode enabled ---",
+            version
+        ));
+    }
 
-        if let Ok(string_var_names) = STRING_VAR_NAMES.read() {
-            if let Ok(mut string_var_values) = STRING_VAR_VALUES.write() {
-                *string_var_values = vec![String::new(); string_var_names.len()];
+    SERIAL_PORT_ENABLED.store(config.serial_enabled, Relaxed);
+    HOTKEY.store(config.hotkey, Relaxed);
+    SYSTEM_VAR_COUNT.store(config.system_var_count, Relaxed);
+    let _ = STORAGE.set(Storage::new(
+        config.var_names.len(),
+        config.trigger_names.len(),
+    ));
+    if let Ok(mut var_names) = VAR_NAMES.write() {
+        *var_names = config.var_names.clone();
+    }
+    if let Ok(mut string_var_names) = STRING_VAR_NAMES.write() {
+        *string_var_names = config.string_var_names.clone();
+    }
+    if let Ok(mut string_var_values) = STRING_VAR_VALUES.write() {
+        *string_var_values = vec![String::new(); config.string_var_names.len()];
+    }
+    if let Ok(mut trigger_names) = TRIGGER_NAMES.write() {
+        *trigger_names = config.trigger_names.clone();
+    }
+    if let Ok(mut trigger_hotkeys) = TRIGGER_HOTKEYS.write() {
+        *trigger_hotkeys = config.trigger_hotkeys.clone();
+    }
+    if !REPLAY.load(Relaxed) {
+        match Distance::load(state_path()) {
+            Ok(loaded) => {
+                if let Ok(mut distance) = DISTANCE.lock() {
+                    *distance = loaded;
+                }
             }
+            Err(e) => log_message(format!("Could not read {}: {}", state_path(), e)),
         }
     }
+    if config.record {
+        record::start(
+            config_path,
+            &config.var_names,
+            config.system_var_count,
+            &config.string_var_names,
+        );
+    }
 
     // GUI Thread
+    #[cfg(windows)]
     thread::spawn(|| {
         run_gui();
     });
 
     // Hotkey Listener Thread
+    #[cfg(windows)]
     thread::spawn(move || {
-        let hotkey = *HOTKEY.get().unwrap_or(&0x79);
         let mut pressed = false;
+        let mut trigger_pressed: Vec<bool> = Vec::new();
         loop {
+            let hotkey = HOTKEY.load(Relaxed);
             unsafe {
                 let state =
                     windows::Win32::UI::Input::KeyboardAndMouse::GetAsyncKeyState(hotkey as i32);
@@ -710,6 +1512,28 @@ pub unsafe extern "stdcall" fn PluginStart(aOwner: uintptr_t) {
                 } else {
                     pressed = false;
                 }
+
+                if let Ok(trigger_hotkeys) = TRIGGER_HOTKEYS.read() {
+                    trigger_pressed.resize(trigger_hotkeys.len(), false);
+                    for (i, (key, target)) in trigger_hotkeys.iter().enumerate() {
+                        let state = windows::Win32::UI::Input::KeyboardAndMouse::GetAsyncKeyState(
+                            *key as i32,
+                        );
+                        if (state as u16 & 0x8000) != 0 {
+                            if !trigger_pressed[i] {
+                                match *target {
+                                    InputTarget::Trigger(idx) => fire_trigger(idx),
+                                    InputTarget::TripReset(trip) => reset_trip(trip),
+                                    InputTarget::Resync => RESYNC_REQUESTED.store(true, Relaxed),
+                                    InputTarget::Variable(_) => {}
+                                }
+                                trigger_pressed[i] = true;
+                            }
+                        } else {
+                            trigger_pressed[i] = false;
+                        }
+                    }
+                }
             }
             thread::sleep(Duration::from_millis(50));
         }
@@ -717,18 +1541,37 @@ pub unsafe extern "stdcall" fn PluginStart(aOwner: uintptr_t) {
 
     let mut vehicle_state = VehicleState::new();
 
-    let portnames_clone = portnames.clone();
-    {
-        let mut ports = SERIAL_PORTS.lock().unwrap();
-        for _ in 0..portnames_clone.len() {
-            ports.push(None);
-        }
+    if let Ok(mut input_mappings) = INPUT_MAPPINGS.write() {
+        *input_mappings = config.input_mappings.clone();
     }
+    let mut outputs = Outputs::new(&config);
+
+    config.profiles[0].activate();
+    let mut active_profile = 0;
+    let mut force = false;
+    let mut reload_check = 0;
+    let mut save_check = 0;
+    let mut blink = BlinkState::default();
+    let mut extra = ExtraState::default();
+    let mut last_resync = Instant::now();
 
     thread::spawn(move || {
         loop {
+            if RESYNC_REQUESTED.swap(false, Relaxed) {
+                log_message("Resending the complete state".to_string());
+                force = true;
+            }
+            if !config.resync_interval.is_zero() && last_resync.elapsed() >= config.resync_interval
+            {
+                force = true;
+            }
+            if force {
+                last_resync = Instant::now();
+            }
+
             // get data from OMSI
-            let newstate = get_vehicle_state_from_omsi(engineonvalue);
+            let newstate =
+                get_vehicle_state_from_omsi(&config, &config.profiles[active_profile], &mut blink);
 
             let verbose = WINDOW_VISIBLE.load(Relaxed);
             let debug = DEBUG_MODE.load(Relaxed);
@@ -738,7 +1581,10 @@ pub unsafe extern "stdcall" fn PluginStart(aOwner: uintptr_t) {
             } else {
                 None
             };
-            let cmdbuf = vehicle_state.compare(&newstate, false, logger);
+            let mut cmdbuf = vehicle_state.compare(&newstate, force, logger);
+            let reverse = newstate.ignition && OMSI_DATA.speed.load(Relaxed) < -REVERSE_SPEED;
+            cmdbuf.extend_from_slice(&build_extra_commands(&config, &mut extra, reverse, force));
+            force = false;
 
             // log when debug=true in config section omsi2komsi
             if verbose && debug && cmdbuf.len() > 0 {
@@ -750,58 +1596,260 @@ pub unsafe extern "stdcall" fn PluginStart(aOwner: uintptr_t) {
             // replace after compare for next round
             vehicle_state = newstate;
 
-            if cmdbuf.len() > 0 && SERIAL_PORT_ENABLED.load(Relaxed) {
-                let mut ports_guard = SERIAL_PORTS.lock().unwrap();
-                for (i, portname_item) in portnames_clone.iter().enumerate() {
-                    if ports_guard[i].is_none() {
-                        match serialport::new(portname_item, baudrate)
-                            .timeout(Duration::from_millis(10))
-                            .open()
-                        {
-                            Ok(mut p) => {
-                                log_message(format!(
-                                    "Serial port {} opened successfully",
-                                    portname_item
-                                ));
-                                // send SimulatorType:OMSI
-                                let mut init_buf = Vec::new();
-                                let simulator_type = KomsiCommand::SimulatorType(0);
-                                init_buf.extend_from_slice(&KomsiCommand::build(&simulator_type));
-                                init_buf.extend_from_slice(&KomsiCommand::build_eol());
-                                if let Err(e) = p.write_all(&init_buf) {
-                                    log_message(format!(
-                                        "Failed to send init string to {}: {}",
-                                        portname_item, e
-                                    ));
-                                }
-                                ports_guard[i] = Some(p);
-                            }
-                            Err(e) => {
-                                log_message(format!(
-                                    "Failed to open serial port {}: {}",
-                                    portname_item, e
-                                ));
-                            }
-                        }
-                    }
+            // for clients that connect now and ports that opened or lost lines, built only when needed
+            let full_state = || {
+                let mut buf = VehicleState::new().compare(&vehicle_state, true, None);
+                buf.extend_from_slice(&build_extra_commands(
+                    &config,
+                    &mut ExtraState::default(),
+                    reverse,
+                    true,
+                ));
+                buf
+            };
+            outputs.send(&config, &cmdbuf, &vehicle_state, &full_state);
 
-                    if let Some(ref mut p) = ports_guard[i] {
-                        if let Err(e) = p.write_all(&cmdbuf) {
-                            log_message(format!(
-                                "Serial write error on {}: {}. Closing port.",
-                                portname_item, e
-                            ));
-                            ports_guard[i] = None;
-                        }
+            // save the distances about every ten seconds, a crash loses at most that much
+            save_check += 1;
+            if save_check >= 100 {
+                save_check = 0;
+                save_distance();
+            }
+
+            // check the config file about once a second
+            reload_check += 1;
+            if reload_check >= 10 {
+                reload_check = 0;
+                let modified = std::fs::metadata(config_path)
+                    .and_then(|m| m.modified())
+                    .ok();
+                if modified != config_modified {
+                    config_modified = modified;
+                    log_message(format!("{} changed, reloading", config_path));
+                    let new_config = load_config(config_path);
+                    let changed = config.bound_sections_changed(&new_config);
+                    if changed.is_empty() {
+                        apply_reloaded_config(&mut config, new_config, &mut outputs);
+                        config.profiles[0].activate();
+                        active_profile = 0;
+                        force = true;
+                    } else {
+                        log_message(format!(
+                            "Reload rejected: {} cannot change while OMSI is running, restart OMSI to apply",
+                            changed.join(", ")
+                        ));
+                        // mapping messages of the rejected config must not hide the old debug mode
+                        DEBUG_MODE.store(debug, Relaxed);
                     }
                 }
             }
 
+            // switch after sending, so the new mappings have been filled by OMSI before the resend
+            let selected = select_profile(&config.profiles);
+            if selected != active_profile {
+                log_message(format!(
+                    "Switching to profile '{}'",
+                    config.profiles[selected].name
+                ));
+                config.profiles[selected].activate();
+                active_profile = selected;
+                force = true;
+            }
+
             thread::sleep(Duration::from_millis(100));
         }
     });
 }
 
+/// Values sent with the configurable KOMSI letters, which the komsi crate has no commands for.
+#[derive(Default)]
+struct ExtraState {
+    /// trip counters in steps of 100 m
+    trips: [Option<u64>; 2],
+    reverse: Option<bool>,
+    /// the encoded texts of `text_commands`
+    texts: Vec<Option<Vec<u8>>>,
+}
+
+/// Builds a KOMSI line with the extra values that changed since they were last sent.
+///
+/// The trips are sent in metres with the letters from `trip1command` and `trip2command` when they
+/// changed by at least 100 m, the reverse flag as `0`/`1` with the letter from `reversecommand`.
+/// The texts of `[stringmappings]` are sent in double quotes after their letter, e.g. `S"Rathaus"`.
+fn build_extra_commands(
+    config: &PluginConfig,
+    sent: &mut ExtraState,
+    reverse: bool,
+    force: bool,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Ok(distance) = DISTANCE.lock() {
+        for (i, letter) in config.trip_commands.iter().enumerate() {
+            let Some(letter) = letter else {
+                continue;
+            };
+            let trip_m = distance.trips_m[i] as u64;
+            if force || sent.trips[i] != Some(trip_m / 100) {
+                sent.trips[i] = Some(trip_m / 100);
+                buf.extend_from_slice(format!("{}{}", letter, trip_m).as_bytes());
+            }
+        }
+    }
+    if let Some(letter) = config.reverse_command
+        && (force || sent.reverse != Some(reverse))
+    {
+        sent.reverse = Some(reverse);
+        buf.extend_from_slice(format!("{}{}", letter, reverse as u8).as_bytes());
+    }
+    if !config.text_commands.is_empty()
+        && let Ok(values) = STRING_VAR_VALUES.read()
+    {
+        // a reloaded config may have a different number of mappings
+        sent.texts.resize(config.text_commands.len(), None);
+        for (i, &(letter, idx, max_len)) in config.text_commands.iter().enumerate() {
+            let value = values.get(idx).map_or("", String::as_str);
+            let text = config.text_encoding.encode(value, max_len);
+            if force || sent.texts[i].as_ref() != Some(&text) {
+                buf.extend_from_slice(format!("{}\"", letter).as_bytes());
+                buf.extend_from_slice(&text);
+                buf.push(b'"');
+                sent.texts[i] = Some(text);
+            }
+        }
+    }
+    if !buf.is_empty() {
+        buf.extend_from_slice(&KomsiCommand::build_eol());
+    }
+    buf
+}
+
+/// Writes the distance counters to the state file if they changed.
+fn save_distance() {
+    if REPLAY.load(Relaxed) {
+        return;
+    }
+    if let Ok(mut distance) = DISTANCE.lock()
+        && let Err(e) = distance.save(state_path())
+    {
+        log_message(format!("Could not write {}: {}", state_path(), e));
+    }
+}
+
+/// Everything the KOMSI lines are sent to.
+struct Outputs {
+    ports: Vec<PortWorker>,
+    udp: UdpOutput,
+    tcp: Option<TcpOutput>,
+    web: Option<WebOutput>,
+    mqtt: Option<MqttOutput>,
+}
+
+impl Outputs {
+    fn new(config: &PluginConfig) -> Outputs {
+        Outputs {
+            ports: spawn_port_workers(config),
+            udp: UdpOutput::new(&config.udp_targets),
+            tcp: config.tcp_listen.as_deref().and_then(TcpOutput::new),
+            web: start_web(config),
+            mqtt: config.mqtt.as_ref().map(MqttOutput::spawn),
+        }
+    }
+
+    /// Sends the changes in `cmdbuf`, `full_state` is for outputs that need the complete state first.
+    ///
+    /// The web server and MQTT compare `state` with the last one themselves, they also send the
+    /// string variables.
+    fn send(
+        &mut self,
+        config: &PluginConfig,
+        cmdbuf: &[u8],
+        state: &VehicleState,
+        full_state: &dyn Fn() -> Vec<u8>,
+    ) {
+        // the port threads write, a slow or missing port never stalls the send loop
+        for (worker, port) in self.ports.iter_mut().zip(&config.ports) {
+            worker.send(port, cmdbuf, full_state);
+        }
+        self.udp.send(cmdbuf);
+        if let Some(tcp) = &mut self.tcp {
+            tcp.send(cmdbuf, full_state);
+        }
+        if let Some(web) = &mut self.web {
+            web.send(state);
+        }
+        if let Some(mqtt) = &mut self.mqtt {
+            mqtt.send(state);
+        }
+    }
+}
+
+fn start_web(config: &PluginConfig) -> Option<WebOutput> {
+    let listen = config.web_listen.as_deref()?;
+    WebOutput::new(listen, config.web_root.clone())
+}
+
+fn spawn_port_workers(config: &PluginConfig) -> Vec<PortWorker> {
+    config
+        .ports
+        .iter()
+        .map(|p| PortWorker::spawn(&p.settings))
+        .collect()
+}
+
+/// Takes over a reloaded config whose bound sections are unchanged.
+///
+/// The outputs whose settings changed are replaced, the old port threads close their ports.
+fn apply_reloaded_config(
+    config: &mut PluginConfig,
+    new_config: PluginConfig,
+    outputs: &mut Outputs,
+) {
+    let port_settings = |c: &PluginConfig| -> Vec<PortSettings> {
+        c.ports.iter().map(|p| p.settings.clone()).collect()
+    };
+    if port_settings(config) != port_settings(&new_config) {
+        log_message("Serial port settings changed, reopening ports".to_string());
+        outputs.ports = spawn_port_workers(&new_config);
+    }
+    if config.udp_targets != new_config.udp_targets {
+        outputs.udp = UdpOutput::new(&new_config.udp_targets);
+    }
+    if config.tcp_listen != new_config.tcp_listen {
+        // the old server has to release its port first
+        outputs.tcp = None;
+        outputs.tcp = new_config.tcp_listen.as_deref().and_then(TcpOutput::new);
+    }
+    if config.web_listen != new_config.web_listen || config.web_root != new_config.web_root {
+        outputs.web = None;
+        outputs.web = start_web(&new_config);
+    }
+    if config.mqtt != new_config.mqtt {
+        outputs.mqtt = new_config.mqtt.as_ref().map(MqttOutput::spawn);
+    }
+    if new_config.record && !config.record {
+        record::start(
+            config_path(),
+            &new_config.var_names,
+            new_config.system_var_count,
+            &new_config.string_var_names,
+        );
+    } else if config.record && !new_config.record {
+        record::stop();
+    }
+
+    SERIAL_PORT_ENABLED.store(new_config.serial_enabled, Relaxed);
+    HOTKEY.store(new_config.hotkey, Relaxed);
+    if let Ok(mut trigger_hotkeys) = TRIGGER_HOTKEYS.write() {
+        *trigger_hotkeys = new_config.trigger_hotkeys.clone();
+    }
+    if let Ok(mut input_mappings) = INPUT_MAPPINGS.write() {
+        *input_mappings = new_config.input_mappings.clone();
+    }
+
+    *config = new_config;
+    log_message("Config reloaded".to_string());
+}
+
 /// This function is called by Omsi 2 to access variables from the plugin.
 ///
 /// Original C declaration:
@@ -819,64 +1867,63 @@ pub unsafe extern "stdcall" fn PluginStart(aOwner: uintptr_t) {
 #[allow(non_snake_case, unused_variables)]
 // #[unsafe(no_mangle)]
 #[unsafe(export_name = "AccessVariable")]
-pub unsafe extern "stdcall" fn AccessVariable(
+pub unsafe extern "system" fn AccessVariable(
     variableIndex: u16,
-    value: *const c_float,
-    writeValue: *const bool,
+    value: *mut c_float,
+    writeValue: *mut bool,
 ) {
     let offset = SYSTEM_VAR_COUNT.load(Relaxed);
     let index = variableIndex as usize + offset;
 
+    if !value.is_null() {
+        record::variable(record::VARIABLE, variableIndex, index, unsafe { *value });
+    }
     handle_variable_access(index, value);
+    handle_variable_write(index, value, writeValue);
 }
 
-fn handle_variable_access(index: usize, value: *const c_float) {
-    let field = {
-        let mut ofield = OmsiDataField::None;
-        if let Ok(names) = VAR_NAMES.read() {
-            if index < names.len() {
-                if index < SHARED_ARRAY_SIZE {
-                    let field_idx = DATA_MAPPING[index].load(Relaxed);
-                    ofield = OmsiDataField::from(field_idx);
-                }
-            }
+/// Hands a value received from the hardware over to OMSI, once per received input.
+fn handle_variable_write(index: usize, value: *mut c_float, write_value: *mut bool) {
+    if value.is_null() || write_value.is_null() {
+        return;
+    }
+    let Some(pending) = STORAGE.get().and_then(|s| s.pending_writes.get(index)) else {
+        return;
+    };
+
+    if pending.pending.swap(false, Acquire) {
+        unsafe {
+            *value = pending.value.load(Relaxed);
+            *write_value = true;
         }
-        ofield
+    }
+}
+
+fn handle_variable_access(index: usize, value: *const c_float) {
+    if value.is_null() {
+        return;
+    }
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    let (Some(var_value), Some(mapping)) = (
+        storage.var_values.get(index),
+        storage.data_mapping.get(index),
+    ) else {
+        warn_index_out_of_range("variable", index);
+        return;
     };
 
+    let val_to_store = unsafe { *value };
+    var_value.store(val_to_store, Relaxed);
+    let field = OmsiDataField::from(mapping.load(Relaxed));
+
     if field == OmsiDataField::None {
         return;
     }
 
-    let f = unsafe { *value };
-    let val_to_store = f as f32;
-
-
-    match field {
-        OmsiDataField::Ignition => OMSI_DATA.ignition.store(val_to_store, Relaxed),
-        OmsiDataField::Engine => OMSI_DATA.engine.store(val_to_store, Relaxed),
-        OmsiDataField::BatteryLight => OMSI_DATA.battery.store(val_to_store, Relaxed),
-        OmsiDataField::Speed => OMSI_DATA.speed.store(val_to_store, Relaxed),
-        OmsiDataField::FrontDoor => OMSI_DATA.front_door.store(val_to_store, Relaxed),
-        OmsiDataField::SecondDoor => OMSI_DATA.second_door.store(val_to_store, Relaxed),
-        OmsiDataField::ThirdDoor => OMSI_DATA.third_door.store(val_to_store, Relaxed),
-        OmsiDataField::StopRequest => OMSI_DATA.stop_request.store(val_to_store, Relaxed),
-        OmsiDataField::MainLights => OMSI_DATA.light_main.store(val_to_store, Relaxed),
-        OmsiDataField::HighBeam => OMSI_DATA.lights_high_beam.store(val_to_store, Relaxed),
-        OmsiDataField::FixingBrake => OMSI_DATA.fixing_brake.store(val_to_store, Relaxed),
-        OmsiDataField::IndicatorLeft => OMSI_DATA.indicator_left.store(val_to_store, Relaxed),
-        OmsiDataField::IndicatorRight => OMSI_DATA.indicator_right.store(val_to_store, Relaxed),
-        OmsiDataField::WarningLights => OMSI_DATA.warning_lights.store(val_to_store, Relaxed),
-        OmsiDataField::Fuel => OMSI_DATA.fuel.store(val_to_store, Relaxed),
-        OmsiDataField::StopBrake => OMSI_DATA.stop_brake.store(val_to_store, Relaxed),
-        OmsiDataField::PassengerDoorsOpen => OMSI_DATA.passenger_doors_open.store(val_to_store, Relaxed),
-        OmsiDataField::DoorClearance => OMSI_DATA.door_clearance.store(val_to_store, Relaxed),
-        OmsiDataField::Time => OMSI_DATA.time.store(val_to_store, Relaxed),
-        OmsiDataField::Day => OMSI_DATA.day.store(val_to_store, Relaxed),
-        OmsiDataField::Month => OMSI_DATA.month.store(val_to_store, Relaxed),
-        OmsiDataField::Year => OMSI_DATA.year.store(val_to_store, Relaxed),
-        OmsiDataField::Odometer => OMSI_DATA.odometer.store(val_to_store, Relaxed),
-        OmsiDataField::None => {}
+    if let Some(data) = OMSI_DATA.field(field) {
+        data.store(val_to_store, Relaxed);
     }
 
     /*
@@ -1138,7 +2185,7 @@ fn handle_variable_access(index: usize, value: *const c_float) {
 #[allow(non_snake_case, unused_variables)]
 // #[unsafe(no_mangle)]
 #[unsafe(export_name = "AccessStringVariable")]
-pub unsafe extern "stdcall" fn AccessStringVariable(
+pub unsafe extern "system" fn AccessStringVariable(
     variable_index: u16,
     pw_char_ptr: *const u16,
     _write_value: *mut bool,
@@ -1175,9 +2222,7 @@ pub unsafe extern "stdcall" fn AccessStringVariable(
                 Err(_) => return,
             };
 
-            let new_string = OsString::from_wide(new_slice)
-                .to_string_lossy()
-                .into_owned();
+            let new_string = String::from_utf16_lossy(new_slice);
 
             let var_name = if let Ok(names) = STRING_VAR_NAMES.read() {
                 names
@@ -1189,6 +2234,7 @@ pub unsafe extern "stdcall" fn AccessStringVariable(
             };
 
             log_message(format!("{} = {}", var_name, new_string));
+            record::text(index, &new_string);
 
             values_write[index] = new_string;
         }
@@ -1226,13 +2272,17 @@ fn is_equal_utf16_to_str(utf16: &[u16], s: &str) -> bool {
 #[allow(non_snake_case, unused_variables)]
 // #[unsafe(no_mangle)]
 #[unsafe(export_name = "AccessSystemVariable")]
-pub unsafe extern "stdcall" fn AccessSystemVariable(
+pub unsafe extern "system" fn AccessSystemVariable(
     variableIndex: u16,
-    value: *const c_float,
-    writeValue: *const bool,
+    value: *mut c_float,
+    writeValue: *mut bool,
 ) {
     let index = variableIndex as usize;
+    if !value.is_null() {
+        record::variable(record::SYSTEM, variableIndex, index, unsafe { *value });
+    }
     handle_variable_access(index, value);
+    handle_variable_write(index, value, writeValue);
 }
 
 /// This function is called by Omsi 2 to access triggers from the plugin.
@@ -1251,7 +2301,44 @@ pub unsafe extern "stdcall" fn AccessSystemVariable(
 #[allow(non_snake_case, unused_variables)]
 // #[unsafe(no_mangle)]
 #[unsafe(export_name = "AccessTrigger")]
-pub unsafe extern "stdcall" fn AccessTrigger(variableIndex: u16, triggerScript: *const bool) {}
+pub unsafe extern "system" fn AccessTrigger(variableIndex: u16, triggerScript: *mut bool) {
+    let index = variableIndex as usize;
+    if triggerScript.is_null() {
+        return;
+    }
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    let (Some(active), Some(pending)) = (
+        storage.active_triggers.get(index),
+        storage.pending_triggers.get(index),
+    ) else {
+        warn_index_out_of_range("trigger", index);
+        return;
+    };
+
+    // a trigger stays active for exactly one callback
+    if active.swap(false, Relaxed) {
+        unsafe {
+            *triggerScript = false;
+        }
+    }
+
+    if pending.swap(false, Relaxed) {
+        if DEBUG_MODE.load(Relaxed) {
+            let trigger_name = TRIGGER_NAMES
+                .read()
+                .ok()
+                .and_then(|names| names.get(index).cloned())
+                .unwrap_or_else(|| format!("Index {}", index));
+            log_message(format!("Trigger {} fired", trigger_name));
+        }
+        unsafe {
+            *triggerScript = true;
+        }
+        active.store(true, Relaxed);
+    }
+}
 
 /// This function is called when the plugin is unloaded by Omsi 2.
 ///
@@ -1265,4 +2352,287 @@ pub unsafe extern "stdcall" fn AccessTrigger(variableIndex: u16, triggerScript:
 #[allow(non_snake_case, unused_variables)]
 // #[unsafe(no_mangle)]
 #[unsafe(export_name = "PluginFinalize")]
-pub unsafe extern "stdcall" fn PluginFinalize() {}
+pub unsafe extern "system" fn PluginFinalize() {
+    save_distance();
+    record::stop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tests share the storage, so each one uses its own indices
+    fn storage() -> &'static Storage {
+        STORAGE.get_or_init(|| Storage::new(16, 16))
+    }
+
+    fn take_write(idx: usize) -> Option<f32> {
+        let write = &storage().pending_writes[idx];
+        write
+            .pending
+            .swap(false, Acquire)
+            .then(|| write.value.load(Relaxed))
+    }
+
+    fn profile(threshold: f32, mappings: &[(&str, &str)]) -> Profile {
+        let settings = omsi_opl::Settings {
+            threshold,
+            ..Default::default()
+        };
+        let var_names = vec!["door_light_1".to_string(), "ai_light".to_string()];
+        let mut profile = Profile::new("default", &settings);
+        for (key, value) in mappings {
+            let entry = Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+                line: 1,
+            };
+            profile.add_data_mapping(&entry, &var_names);
+        }
+        profile
+    }
+
+    #[test]
+    fn comparisons_ignore_the_threshold() {
+        // 0/2 lamps need threshold = 1 for the plain mappings
+        let p = profile(
+            1.0,
+            &[
+                ("FrontDoor", "door_light_1 >= 1"),
+                ("MainLights", "ai_light"),
+            ],
+        );
+        let door = p.expr_mappings[0].1.eval(&|idx| [1.0, 0.0][idx]);
+        assert!(p.is_on(OmsiDataField::FrontDoor, door));
+        assert!(!p.is_on(OmsiDataField::FrontDoor, 0.0));
+        assert!(!p.is_on(OmsiDataField::MainLights, 1.0));
+        assert!(p.is_on(OmsiDataField::MainLights, 2.0));
+
+        let p = profile(-1.0, &[("BatteryLight", "!door_light_1")]);
+        assert!(!p.is_on(OmsiDataField::BatteryLight, 0.0));
+        assert!(p.is_on(OmsiDataField::BatteryLight, 1.0));
+        assert!(p.is_on(OmsiDataField::MainLights, 0.0));
+    }
+
+    #[test]
+    fn komsi_letter_of_fields() {
+        assert_eq!(komsi_letter("speed"), Some(b'y'));
+        assert_eq!(komsi_letter("DateTime"), Some(b'r'));
+        assert_eq!(komsi_letter("q"), Some(b'q'));
+        assert_eq!(komsi_letter("tempo"), None);
+        assert_eq!(komsi_letter("1"), None);
+    }
+
+    #[test]
+    fn filter_digit_runs() {
+        assert_eq!(filter_komsi(b"A1B0y123s80\n", b"Ay"), b"A1y123\n");
+        assert_eq!(filter_komsi(b"A1B0y123s80\n", b"s"), b"s80\n");
+    }
+
+    #[test]
+    fn filter_datetime() {
+        let buf = b"y5r20261018143000o12345\n";
+        assert_eq!(filter_komsi(buf, b"r"), b"r20261018143000\n");
+        assert_eq!(filter_komsi(buf, b"yo"), b"y5o12345\n");
+    }
+
+    #[test]
+    fn filter_quoted_text() {
+        let buf = b"y5Z\"Linie 42 A1 y7\"B1\n";
+        assert_eq!(filter_komsi(buf, b"Z"), b"Z\"Linie 42 A1 y7\"\n");
+        assert_eq!(filter_komsi(buf, b"yB"), b"y5B1\n");
+        assert_eq!(filter_komsi(b"Z\"\"y1\n", b"Zy"), b"Z\"\"y1\n");
+    }
+
+    #[test]
+    fn filter_lines() {
+        let buf = b"A1B1\ny5\nB0\n";
+        assert_eq!(filter_komsi(buf, b"AB"), b"A1B1\nB0\n");
+        // lines without a kept command leave no empty line behind
+        assert_eq!(filter_komsi(buf, b"H"), b"");
+        assert_eq!(filter_komsi(b"", b"A"), b"");
+    }
+
+    #[test]
+    fn komsi_letters_are_reserved() {
+        for (_, letter) in KOMSI_FIELDS {
+            assert!(KOMSI_LETTERS.contains(*letter as char));
+        }
+    }
+
+    #[test]
+    fn all_fields_in_order() {
+        assert_eq!(OmsiDataField::from(0), OmsiDataField::None);
+        for (i, field) in OmsiDataField::ALL.into_iter().enumerate() {
+            assert_eq!(field as usize, i + 1);
+            assert_eq!(OmsiDataField::from(i + 1), field);
+        }
+        assert_eq!(
+            OmsiDataField::from(OmsiDataField::ALL.len() + 1),
+            OmsiDataField::None
+        );
+    }
+
+    #[test]
+    fn komsi_lines() {
+        assert_eq!(
+            parse_komsi_line(b"K1H0"),
+            vec![
+                KomsiCommand::StopRequest(true),
+                KomsiCommand::FrontDoor(false)
+            ]
+        );
+        assert_eq!(parse_komsi_line(b"y42\r"), vec![KomsiCommand::Speed(42)]);
+        // stray characters and letters KOMSI does not know are skipped
+        assert_eq!(
+            parse_komsi_line(b" 7K1-;H1"),
+            vec![
+                KomsiCommand::StopRequest(true),
+                KomsiCommand::FrontDoor(true)
+            ]
+        );
+        assert_eq!(
+            parse_komsi_line(b"Q5K1"),
+            vec![KomsiCommand::StopRequest(true)]
+        );
+        assert!(parse_komsi_line(b"").is_empty());
+
+        assert_eq!(
+            input_command_value(&KomsiCommand::StopRequest(true)),
+            Some(("stoprequest", 1.0))
+        );
+        assert_eq!(
+            input_command_value(&KomsiCommand::Speed(42)),
+            Some(("speed", 42.0))
+        );
+    }
+
+    #[test]
+    fn input_writes_variables() {
+        let mappings = vec![
+            ("stoprequest".to_string(), InputTarget::Variable(3)),
+            ("frontdoor".to_string(), InputTarget::Variable(4)),
+        ];
+        storage();
+        apply_input("stoprequest", 1.0, &mappings);
+        assert_eq!(take_write(3), Some(1.0));
+        assert_eq!(take_write(3), None);
+        assert_eq!(take_write(4), None);
+        apply_input("highbeam", 1.0, &mappings);
+        assert_eq!(take_write(3), None);
+        // out of range does nothing
+        apply_input(
+            "frontdoor",
+            1.0,
+            &[("frontdoor".to_string(), InputTarget::Variable(99))],
+        );
+
+        // lines may arrive in pieces
+        let mut line_buf = Vec::new();
+        handle_input(&mut line_buf, b"K0H", &mappings);
+        assert_eq!(take_write(3), None);
+        handle_input(&mut line_buf, b"1\r\nK", &mappings);
+        assert_eq!(take_write(3), Some(0.0));
+        assert_eq!(take_write(4), Some(1.0));
+        assert_eq!(line_buf, b"K");
+    }
+
+    #[test]
+    fn input_without_line_feed_is_discarded() {
+        let mappings = vec![("stoprequest".to_string(), InputTarget::Variable(5))];
+        storage();
+        let mut line_buf = Vec::new();
+        handle_input(&mut line_buf, &[b'0'; INPUT_LINE_MAX], &mappings);
+        assert_eq!(line_buf.len(), INPUT_LINE_MAX);
+        handle_input(&mut line_buf, b"K1", &mappings);
+        assert!(line_buf.is_empty());
+        // the discarded K1 is lost, the next complete line counts again
+        handle_input(&mut line_buf, b"\nK1\n", &mappings);
+        assert_eq!(take_write(5), Some(1.0));
+        assert!(line_buf.is_empty());
+    }
+
+    #[test]
+    fn triggers_are_active_for_one_callback() {
+        storage();
+        let mut active = false;
+        fire_trigger(6);
+        for expected in [true, false, false] {
+            unsafe { AccessTrigger(6, &mut active) };
+            assert_eq!(active, expected);
+        }
+
+        // out of range and null are ignored
+        fire_trigger(99);
+        unsafe {
+            AccessTrigger(99, &mut active);
+            AccessTrigger(6, std::ptr::null_mut());
+        }
+        assert!(!active);
+    }
+
+    fn config(name: &str, content: &str) -> PluginConfig {
+        let path = std::env::temp_dir().join(format!(
+            "omsi2komsi_test_{}_{}.opl",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, content).unwrap();
+        let config = load_config(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+        config
+    }
+
+    #[test]
+    fn only_unbound_sections_reload() {
+        let base = "\
+[varlist]
+2
+elec_busbar_main
+door_light_1
+[triggers]
+1
+bus_doorfront0
+[datamappings]
+Ignition = elec_busbar_main
+";
+        let loaded = config("base", base);
+        assert!(
+            loaded
+                .bound_sections_changed(&config("same", base))
+                .is_empty()
+        );
+
+        let mappings = base.replace("Ignition = elec_busbar_main", "FrontDoor = door_light_1");
+        assert!(
+            loaded
+                .bound_sections_changed(&config("datamappings", &mappings))
+                .is_empty()
+        );
+
+        let varlist = base.replace("door_light_1\n", "door_light_2\n");
+        assert_eq!(
+            loaded.bound_sections_changed(&config("varlist", &varlist)),
+            vec!["[varlist]"]
+        );
+        let both = varlist.replace("bus_doorfront0", "bus_doorfront1");
+        assert_eq!(
+            loaded.bound_sections_changed(&config("both", &both)),
+            vec!["[varlist]", "[triggers]"]
+        );
+    }
+
+    #[test]
+    fn shipped_files_are_valid() {
+        assert!(is_data_field("passengerdoorsopen"));
+        assert!(!is_data_field("passengerdooropen"));
+        for content in [
+            include_str!("../omsi2komsi.opl"),
+            include_str!("../omsilogger.opl"),
+        ] {
+            let opl = Opl::parse(content);
+            assert_eq!(opl.diagnostics, vec![]);
+            assert_eq!(opl.validate(&is_data_field, MAX_VARIABLES), vec![]);
+        }
+    }
+}
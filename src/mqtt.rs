@@ -0,0 +1,620 @@
+//! MQTT client for home cockpits: publishes every changed field as a retained message and passes
+//! messages on the `set` topics to `[inputmappings]`.
+//!
+//! Speaks the small part of MQTT 3.1.1 this needs, QoS 0 only, so any broker like Mosquitto works.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{
+    Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError, sync_channel,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use komsi::vehicle::VehicleState;
+
+use crate::fields::Fields;
+use crate::{INPUT_MAPPINGS, apply_input, log_message};
+
+// rounds of the send loop waiting for the broker, further rounds are dropped and resent later
+const QUEUE_LEN: usize = 64;
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+// how long a read waits, also the delay before queued messages are written
+const POLL: Duration = Duration::from_millis(20);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+/// The MQTT settings of `[omsi2komsi]` and `[mqtttopics]`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MqttSettings {
+    /// `host:port`
+    pub broker: String,
+    pub client_id: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// topic prefix, e.g. `omsi/bus`
+    pub prefix: String,
+    /// lowercase field or string variable name and its topic, an empty topic is not published
+    pub topics: Vec<(String, String)>,
+}
+
+impl MqttSettings {
+    /// Returns the topic of a field, `<prefix>/<field>` or `<prefix>/string/<name>` unless configured.
+    fn topic(&self, name: &str, string: bool) -> Option<String> {
+        if let Some((_, topic)) = self.topics.iter().find(|(n, _)| n == name) {
+            return Some(topic.clone()).filter(|t| !t.is_empty());
+        }
+        // wildcards are not allowed in the topic of a message
+        let name = name.replace(['+', '#'], "_");
+        if string {
+            Some(format!("{}/string/{}", self.prefix, name))
+        } else {
+            Some(format!("{}/{}", self.prefix, name))
+        }
+    }
+}
+
+/// The send loop's handle to the MQTT thread, dropping it disconnects from the broker.
+pub struct MqttOutput {
+    settings: MqttSettings,
+    queue: SyncSender<Vec<u8>>,
+    /// set by the thread after connecting and after lost messages, all fields are published next
+    resync: Arc<AtomicBool>,
+    overflow: bool,
+    last: Fields,
+}
+
+impl MqttOutput {
+    pub fn spawn(settings: &MqttSettings) -> MqttOutput {
+        let (queue, receiver) = sync_channel(QUEUE_LEN);
+        let resync = Arc::new(AtomicBool::new(false));
+        let thread_settings = settings.clone();
+        let thread_resync = resync.clone();
+        thread::spawn(move || run(thread_settings, receiver, thread_resync));
+        MqttOutput {
+            settings: settings.clone(),
+            queue,
+            resync,
+            overflow: false,
+            last: Fields::default(),
+        }
+    }
+
+    /// Queues the fields that changed since the last call as retained messages.
+    pub fn send(&mut self, state: &VehicleState) {
+        let fields = Fields::read(state);
+        let changed = if self.resync.swap(false, Relaxed) {
+            fields.clone()
+        } else {
+            fields.changed_since(&self.last)
+        };
+        self.last = fields;
+
+        let mut buf = Vec::new();
+        for (name, value) in &changed.vehicle {
+            if let Some(topic) = self.settings.topic(name, false) {
+                buf.extend_from_slice(&publish(&topic, value.text().as_bytes(), true));
+            }
+        }
+        for (name, value) in &changed.strings {
+            if let Some(topic) = self.settings.topic(name, true) {
+                buf.extend_from_slice(&publish(&topic, value.as_bytes(), true));
+            }
+        }
+        if buf.is_empty() {
+            return;
+        }
+
+        match self.queue.try_send(buf) {
+            Ok(()) => self.overflow = false,
+            Err(TrySendError::Full(_)) => {
+                if !self.overflow {
+                    log_message(format!(
+                        "MQTT broker {} cannot keep up, dropping messages",
+                        self.settings.broker
+                    ));
+                    self.overflow = true;
+                }
+                self.resync.store(true, Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Keeps the connection to the broker, with backoff while it is unreachable.
+fn run(settings: MqttSettings, queue: Receiver<Vec<u8>>, resync: Arc<AtomicBool>) {
+    let mut backoff = BACKOFF_MIN;
+    // logged once until connected again
+    let mut failing = false;
+
+    loop {
+        match connect(&settings) {
+            Ok(stream) => {
+                log_message(format!("MQTT connected to {}", settings.broker));
+                failing = false;
+                backoff = BACKOFF_MIN;
+                // a restarted broker has lost the retained messages
+                resync.store(true, Relaxed);
+                match serve(stream, &settings, &queue) {
+                    Ok(()) => return,
+                    Err(msg) => log_message(format!(
+                        "MQTT connection to {} lost: {}",
+                        settings.broker, msg
+                    )),
+                }
+            }
+            Err(msg) => {
+                if !failing {
+                    log_message(format!("{}, retrying in the background", msg));
+                    failing = true;
+                }
+                // messages for a missing broker are dropped, the resync after connecting replaces them
+                let retry_at = Instant::now() + backoff;
+                while let Some(wait) = retry_at.checked_duration_since(Instant::now()) {
+                    match queue.recv_timeout(wait) {
+                        Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                        // the config changed or the plugin is unloaded
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                backoff = (backoff * 2).min(BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+/// Opens the connection and subscribes to the `set` topics, the error is the message to log.
+fn connect(settings: &MqttSettings) -> Result<TcpStream, String> {
+    let broker = &settings.broker;
+    let addr = broker
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("MQTT broker {} not found", broker))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+        .map_err(|e| format!("Could not connect to MQTT broker {}: {}", broker, e))?;
+    let error = |e: std::io::Error| format!("MQTT broker {}: {}", broker, e);
+    stream.set_nodelay(true).map_err(error)?;
+    stream
+        .set_write_timeout(Some(WRITE_TIMEOUT))
+        .map_err(error)?;
+    stream
+        .set_read_timeout(Some(CONNECT_TIMEOUT))
+        .map_err(error)?;
+
+    stream.write_all(&connect_packet(settings)).map_err(error)?;
+    let mut input = Vec::new();
+    let connack = loop {
+        if let Some(packet) = take_packet(&mut input) {
+            break packet;
+        }
+        let mut buf = [0u8; 256];
+        match stream.read(&mut buf) {
+            Ok(0) => return Err(format!("MQTT broker {} closed the connection", broker)),
+            Ok(n) => input.extend_from_slice(&buf[..n]),
+            Err(e) => return Err(error(e)),
+        }
+    };
+    match connack {
+        (CONNACK, body) if body.get(1) == Some(&0) => {}
+        (CONNACK, body) => {
+            let reason = match body.get(1) {
+                Some(1) => "unsupported protocol version",
+                Some(2) => "client id rejected",
+                Some(3) => "server unavailable",
+                Some(4) => "bad user name or password",
+                Some(5) => "not authorized",
+                _ => "unknown reason",
+            };
+            return Err(format!(
+                "MQTT broker {} refused the connection: {}",
+                broker, reason
+            ));
+        }
+        _ => return Err(format!("MQTT broker {} sent no CONNACK", broker)),
+    }
+
+    let mut subscribe = vec![0, 1];
+    push_str(&mut subscribe, &format!("{}/set/+", settings.prefix));
+    subscribe.push(0);
+    stream
+        .write_all(&packet(SUBSCRIBE, &subscribe))
+        .map_err(error)?;
+    // marks the panels as online, the will of the connect packet resets it
+    stream
+        .write_all(&publish(&online_topic(settings), b"1", true))
+        .map_err(error)?;
+    Ok(stream)
+}
+
+/// Writes the queued messages and reads the `set` topics until the connection breaks.
+///
+/// Returns `Ok` when the queue is closed, i.e. the output was dropped.
+fn serve(
+    mut stream: TcpStream,
+    settings: &MqttSettings,
+    queue: &Receiver<Vec<u8>>,
+) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(POLL))
+        .map_err(|e| e.to_string())?;
+    let mut input = Vec::new();
+    // pinged on a timer of its own, writes alone do not show that the broker still answers
+    let mut last_ping = Instant::now();
+    let mut ping_sent: Option<Instant> = None;
+    let set_prefix = format!("{}/set/", settings.prefix);
+
+    loop {
+        loop {
+            match queue.try_recv() {
+                Ok(buf) => stream.write_all(&buf).map_err(|e| e.to_string())?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // a clean disconnect keeps the will, so the online topic is reset by hand
+                    let _ = stream.write_all(&publish(&online_topic(settings), b"0", true));
+                    let _ = stream.write_all(&packet(DISCONNECT, &[]));
+                    return Ok(());
+                }
+            }
+        }
+
+        if ping_sent.is_none() && last_ping.elapsed() >= KEEP_ALIVE / 2 {
+            stream
+                .write_all(&packet(PINGREQ, &[]))
+                .map_err(|e| e.to_string())?;
+            last_ping = Instant::now();
+            ping_sent = Some(last_ping);
+        }
+        // the broker answers every ping, without a PINGRESP the connection is gone
+        if ping_sent.is_some_and(|sent| sent.elapsed() >= KEEP_ALIVE / 2) {
+            return Err("no answer from the broker".to_string());
+        }
+
+        let mut buf = [0u8; 1024];
+        match stream.read(&mut buf) {
+            Ok(0) => return Err("closed by the broker".to_string()),
+            Ok(n) => input.extend_from_slice(&buf[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+
+        while let Some((header, body)) = take_packet(&mut input) {
+            match header & 0xf0 {
+                PUBLISH => {
+                    let Some((topic, payload)) = parse_publish(header, &body) else {
+                        continue;
+                    };
+                    let Some(name) = topic.strip_prefix(&set_prefix) else {
+                        continue;
+                    };
+                    let payload = String::from_utf8_lossy(payload);
+                    let Some(value) = parse_value(&payload) else {
+                        log_message(format!("MQTT: invalid value '{}' on {}", payload, topic));
+                        continue;
+                    };
+                    if let Ok(input_mappings) = INPUT_MAPPINGS.read() {
+                        apply_input(&name.to_lowercase(), value, &input_mappings);
+                    }
+                }
+                PINGRESP => ping_sent = None,
+                SUBACK if body.get(2) == Some(&0x80) => {
+                    log_message(format!(
+                        "MQTT broker refused the subscription to {}+",
+                        set_prefix
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn online_topic(settings: &MqttSettings) -> String {
+    format!("{}/online", settings.prefix)
+}
+
+/// Input values: numbers, or `true`/`on` and `false`/`off`.
+fn parse_value(payload: &str) -> Option<f32> {
+    let payload = payload.trim();
+    if payload.eq_ignore_ascii_case("true") || payload.eq_ignore_ascii_case("on") {
+        Some(1.0)
+    } else if payload.eq_ignore_ascii_case("false") || payload.eq_ignore_ascii_case("off") {
+        Some(0.0)
+    } else {
+        payload.parse().ok()
+    }
+}
+
+fn connect_packet(settings: &MqttSettings) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_str(&mut body, "MQTT");
+    // protocol level 4 is MQTT 3.1.1, clean session and a retained will
+    body.push(4);
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if settings.user.is_some() {
+        flags |= 0x80;
+    }
+    if settings.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    push_str(&mut body, &settings.client_id);
+    push_str(&mut body, &online_topic(settings));
+    push_str(&mut body, "0");
+    if let Some(user) = &settings.user {
+        push_str(&mut body, user);
+    }
+    if let Some(password) = &settings.password {
+        push_str(&mut body, password);
+    }
+    packet(CONNECT, &body)
+}
+
+fn publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_str(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, &body)
+}
+
+/// Returns topic and payload of a received PUBLISH packet.
+fn parse_publish(header: u8, body: &[u8]) -> Option<(String, &[u8])> {
+    let len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    let topic = String::from_utf8_lossy(body.get(2..2 + len)?).into_owned();
+    // QoS 1 and 2 have a packet id, the subscription asks for QoS 0 only
+    let start = if header & 0x06 == 0 { 2 + len } else { 4 + len };
+    Some((topic, body.get(start..)?))
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Removes the first complete packet from `input`.
+fn take_packet(input: &mut Vec<u8>) -> Option<(u8, Vec<u8>)> {
+    let mut len = 0;
+    let mut pos = 1;
+    loop {
+        let byte = *input.get(pos)?;
+        len += ((byte & 0x7f) as usize) << (7 * (pos - 1));
+        pos += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if pos > 4 {
+            // not MQTT, start over with the next data
+            input.clear();
+            return None;
+        }
+    }
+    if input.len() < pos + len {
+        return None;
+    }
+    let header = input[0];
+    let body = input[pos..pos + len].to_vec();
+    input.drain(..pos + len);
+    Some((header, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(client_id: &str) -> MqttSettings {
+        MqttSettings {
+            broker: "127.0.0.1:1883".to_string(),
+            client_id: client_id.to_string(),
+            user: None,
+            password: None,
+            prefix: "omsi2komsi/test".to_string(),
+            topics: vec![],
+        }
+    }
+
+    #[test]
+    fn remaining_length() {
+        assert_eq!(packet(PINGREQ, &[]), [0xc0, 0]);
+        assert_eq!(packet(PUBLISH, &[7; 127])[..2], [0x30, 127]);
+        assert_eq!(packet(PUBLISH, &[7; 128])[..3], [0x30, 0x80, 0x01]);
+        assert_eq!(packet(PUBLISH, &[7; 321])[..3], [0x30, 0xc1, 0x02]);
+        assert_eq!(
+            packet(PUBLISH, &vec![7; 2_097_152])[..5],
+            [0x30, 0x80, 0x80, 0x80, 0x01]
+        );
+
+        for len in [0, 127, 128, 321, 16_384, 2_097_152] {
+            let body: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut input = packet(PUBLISH, &body);
+            assert_eq!(take_packet(&mut input), Some((PUBLISH, body)));
+            assert!(input.is_empty());
+        }
+    }
+
+    #[test]
+    fn split_reads() {
+        let first = packet(PUBLISH, &[1; 200]);
+        let second = packet(SUBACK, &[0, 1, 0]);
+        let data = [first.clone(), second].concat();
+
+        let mut input = Vec::new();
+        // the packet is only complete with its last byte, even in the middle of the length
+        for &byte in &data[..first.len() - 1] {
+            input.push(byte);
+            assert_eq!(take_packet(&mut input), None);
+        }
+        input.extend_from_slice(&data[first.len() - 1..]);
+        assert_eq!(take_packet(&mut input), Some((PUBLISH, vec![1; 200])));
+        assert_eq!(take_packet(&mut input), Some((SUBACK, vec![0, 1, 0])));
+        assert_eq!(take_packet(&mut input), None);
+    }
+
+    #[test]
+    fn length_over_four_bytes() {
+        let mut input = vec![0x30, 0xff, 0xff, 0xff, 0xff, 0x01, 0, 0];
+        assert_eq!(take_packet(&mut input), None);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn publish_qos() {
+        let (header, body) = take_packet(&mut publish("omsi/bus/set/door", b"on", true)).unwrap();
+        assert_eq!(header, PUBLISH | 1);
+        assert_eq!(
+            parse_publish(header, &body),
+            Some(("omsi/bus/set/door".to_string(), &b"on"[..]))
+        );
+
+        // QoS 1 with packet id 0x0102
+        let mut body = Vec::new();
+        push_str(&mut body, "omsi/bus/set/door");
+        body.extend_from_slice(&[1, 2]);
+        body.extend_from_slice(b"1");
+        assert_eq!(
+            parse_publish(PUBLISH | 0x02, &body),
+            Some(("omsi/bus/set/door".to_string(), &b"1"[..]))
+        );
+
+        assert_eq!(parse_publish(PUBLISH, &[0]), None);
+        assert_eq!(parse_publish(PUBLISH, &[0, 5, b'a']), None);
+        assert_eq!(parse_publish(PUBLISH | 0x02, &[0, 1, b'a', 0]), None);
+    }
+
+    #[test]
+    fn connect_flags() {
+        let (header, body) = take_packet(&mut connect_packet(&settings("bus"))).unwrap();
+        assert_eq!(header, CONNECT);
+        assert_eq!(body[..7], [0, 4, b'M', b'Q', b'T', b'T', 4]);
+        // clean session, will, retained will
+        assert_eq!(body[7], 0x26);
+        assert_eq!(body[8..10], [0, 30]);
+        let mut rest = Vec::new();
+        push_str(&mut rest, "bus");
+        push_str(&mut rest, "omsi2komsi/test/online");
+        push_str(&mut rest, "0");
+        assert_eq!(body[10..], rest);
+
+        let mut with_login = settings("bus");
+        with_login.user = Some("driver".to_string());
+        assert_eq!(
+            take_packet(&mut connect_packet(&with_login)).unwrap().1[7],
+            0xa6
+        );
+        with_login.password = Some("secret".to_string());
+        let body = take_packet(&mut connect_packet(&with_login)).unwrap().1;
+        assert_eq!(body[7], 0xe6);
+        assert!(body.ends_with(b"\0\x06driver\0\x06secret"));
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(parse_value("1"), Some(1.0));
+        assert_eq!(parse_value(" 2.5\n"), Some(2.5));
+        assert_eq!(parse_value("-3"), Some(-3.0));
+        assert_eq!(parse_value("ON"), Some(1.0));
+        assert_eq!(parse_value("true"), Some(1.0));
+        assert_eq!(parse_value("Off"), Some(0.0));
+        assert_eq!(parse_value("false"), Some(0.0));
+        assert_eq!(parse_value(""), None);
+        assert_eq!(parse_value("open"), None);
+    }
+
+    #[test]
+    fn topics() {
+        let mut settings = settings("bus");
+        settings.prefix = "omsi/bus".to_string();
+        settings.topics = vec![
+            ("speed".to_string(), "cockpit/tacho".to_string()),
+            ("fuel".to_string(), String::new()),
+        ];
+        assert_eq!(
+            settings.topic("speed", false),
+            Some("cockpit/tacho".to_string())
+        );
+        assert_eq!(settings.topic("fuel", false), None);
+        assert_eq!(
+            settings.topic("lights_stop_request", false),
+            Some("omsi/bus/lights_stop_request".to_string())
+        );
+        assert_eq!(
+            settings.topic("kennzeichen", true),
+            Some("omsi/bus/string/kennzeichen".to_string())
+        );
+        assert_eq!(
+            settings.topic("a+b#", true),
+            Some("omsi/bus/string/a_b_".to_string())
+        );
+    }
+
+    /// Reads until a packet of the given type arrives.
+    fn read_packet(stream: &mut TcpStream, input: &mut Vec<u8>, kind: u8) -> (u8, Vec<u8>) {
+        loop {
+            while let Some((header, body)) = take_packet(input) {
+                if header & 0xf0 == kind {
+                    return (header, body);
+                }
+            }
+            let mut buf = [0u8; 256];
+            let n = stream.read(&mut buf).expect("no answer from the broker");
+            assert!(n > 0, "closed by the broker");
+            input.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    // run with: mosquitto -p 1883 & cargo test mosquitto -- --ignored
+    #[test]
+    #[ignore = "needs an MQTT broker like mosquitto on 127.0.0.1:1883"]
+    fn mosquitto() {
+        let mut panel = connect(&settings("omsi2komsi-test-panel")).unwrap();
+        let mut plugin = connect(&settings("omsi2komsi-test-plugin")).unwrap();
+        let mut panel_input = Vec::new();
+        let mut plugin_input = Vec::new();
+        read_packet(&mut panel, &mut panel_input, SUBACK);
+
+        plugin.write_all(&packet(PINGREQ, &[])).unwrap();
+        read_packet(&mut plugin, &mut plugin_input, PINGRESP);
+
+        plugin
+            .write_all(&publish("omsi2komsi/test/set/door", b"on", false))
+            .unwrap();
+        let (header, body) = read_packet(&mut panel, &mut panel_input, PUBLISH);
+        assert_eq!(
+            parse_publish(header, &body),
+            Some(("omsi2komsi/test/set/door".to_string(), &b"on"[..]))
+        );
+    }
+}
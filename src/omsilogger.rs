@@ -1,15 +1,15 @@
+#![cfg(windows)]
 #[cfg(not(target_arch = "x86"))]
 compile_error!("This plugin must be compiled for x86 (32-bit) to be compatible with OMSI!");
 
 use libc::{c_char, c_float};
+use omsi_opl::Opl;
 use std::ffi::OsString;
-use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::io::{BufRead, BufReader};
 use std::os::windows::ffi::OsStringExt;
 use std::slice;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::Relaxed};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering::Relaxed};
 use std::sync::{Mutex, OnceLock, RwLock};
 use std::thread;
 use std::time::Duration;
@@ -17,15 +17,16 @@ use std::time::Duration;
 #[allow(non_camel_case_types)]
 pub type uintptr_t = usize;
 
-const SHARED_ARRAY_SIZE: usize = 100;
-
-static SHARED_ARRAY: [AtomicU32; SHARED_ARRAY_SIZE] = {
-    const ARRAY_REPEAT_VALUE: AtomicU32 = AtomicU32::new(0);
-    [ARRAY_REPEAT_VALUE; SHARED_ARRAY_SIZE]
-};
+// one value per system and user variable, sized from the opl in PluginStart
+static SHARED_ARRAY: OnceLock<Box<[AtomicU32]>> = OnceLock::new();
+// first index OMSI used beyond the lists in the opl, usize::MAX if there was none
+static OUT_OF_RANGE_INDEX: AtomicUsize = AtomicUsize::new(usize::MAX);
 
 static VAR_NAMES: OnceLock<Vec<String>> = OnceLock::new();
 static STRING_VAR_NAMES: OnceLock<Vec<String>> = OnceLock::new();
+static TRIGGER_NAMES: OnceLock<Vec<String>> = OnceLock::new();
+// last seen state per trigger: 0 = not called yet, 1 = inactive, 2 = active
+static TRIGGER_STATES: RwLock<Vec<u8>> = RwLock::new(Vec::new());
 static STRING_VAR_VALUES: RwLock<Vec<String>> = RwLock::new(Vec::new());
 static HOTKEY: OnceLock<u32> = OnceLock::new();
 static LOG_MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
@@ -37,120 +38,33 @@ static SYSTEM_VAR_COUNT: AtomicU32 = AtomicU32::new(0);
 pub unsafe extern "stdcall" fn PluginStart(_a_owner: uintptr_t) {
     let opl_path = ".\\plugins\\omsilogger.opl";
 
-    // Read varlist from .opl file manually
-    let mut var_names = Vec::new();
-    let mut string_var_names = Vec::new();
-    let mut hotkey_val = 0x79; // Default F10
-    if let Ok(file) = File::open(opl_path) {
-        let reader = BufReader::new(file);
-        let mut in_varlist = false;
-        let mut in_systemvarlist = false;
-        let mut in_stringvarlist = false;
-        let mut in_hotkey = false;
-        let mut count = 0;
-        let mut expected_count = 0;
-        let mut system_var_names = Vec::new();
-
-        for line in reader.lines() {
-            if let Ok(l) = line {
-                let l = l.trim();
-                if l == "[varlist]" {
-                    in_varlist = true;
-                    in_systemvarlist = false;
-                    in_hotkey = false;
-                    count = 0;
-                    expected_count = 0;
-                    continue;
-                }
-                if l == "[systemvarlist]" {
-                    in_systemvarlist = true;
-                    in_varlist = false;
-                    in_stringvarlist = false;
-                    in_hotkey = false;
-                    count = 0;
-                    expected_count = 0;
-                    continue;
-                }
-                if l == "[stringvarlist]" {
-                    in_stringvarlist = true;
-                    in_varlist = false;
-                    in_systemvarlist = false;
-                    in_hotkey = false;
-                    count = 0;
-                    expected_count = 0;
-                    continue;
-                }
-                if l == "[hotkey]" {
-                    in_hotkey = true;
-                    in_varlist = false;
-                    in_systemvarlist = false;
-                    in_stringvarlist = false;
-                    continue;
-                }
-                if in_systemvarlist {
-                    if expected_count == 0 {
-                        if let Ok(c) = l.parse::<usize>() {
-                            expected_count = c;
-                            if expected_count == 0 { in_systemvarlist = false; }
-                        }
-                    } else {
-                        system_var_names.push(l.to_string());
-                        count += 1;
-                        if count >= expected_count {
-                            in_systemvarlist = false;
-                        }
-                    }
-                }
-                if in_varlist {
-                    if expected_count == 0 {
-                        if let Ok(c) = l.parse::<usize>() {
-                            expected_count = c;
-                            if expected_count == 0 { in_varlist = false; }
-                        }
-                    } else {
-                        var_names.push(l.to_string());
-                        count += 1;
-                        if count >= expected_count {
-                            in_varlist = false;
-                        }
-                    }
-                }
-                if in_stringvarlist {
-                    if expected_count == 0 {
-                        if let Ok(c) = l.parse::<usize>() {
-                            expected_count = c;
-                            if expected_count == 0 { in_stringvarlist = false; }
-                        }
-                    } else {
-                        string_var_names.push(l.to_string());
-                        count += 1;
-                        if count >= expected_count {
-                            in_stringvarlist = false;
-                        }
-                    }
-                }
-                if in_hotkey {
-                    if l.starts_with("0x") {
-                        if let Ok(h) = u32::from_str_radix(&l[2..], 16) {
-                            hotkey_val = h;
-                        }
-                    } else if let Ok(h) = l.parse::<u32>() {
-                        hotkey_val = h;
-                    }
-                }
-            }
-        }
-        SYSTEM_VAR_COUNT.store(system_var_names.len() as u32, Relaxed);
-        let mut combined_names = system_var_names;
-        combined_names.extend(var_names);
-        var_names = combined_names;
-    }
-
+    let mut diagnostics = Vec::new();
+    let opl = Opl::load(opl_path).unwrap_or_else(|e| {
+        diagnostics.push(format!("Could not read {}: {}", opl_path, e));
+        Opl::default()
+    });
+    diagnostics.extend(
+        opl.diagnostics
+            .iter()
+            .map(|d| format!("{}: {}", opl_path, d)),
+    );
+
+    SYSTEM_VAR_COUNT.store(opl.systemvarlist.len() as u32, Relaxed);
+    let var_names = opl.var_names();
+    let string_var_names = opl.stringvarlist.names;
+    let trigger_names = opl.triggers.names;
+    let hotkey_val = opl.hotkey;
+
+    let _ = SHARED_ARRAY.set(var_names.iter().map(|_| AtomicU32::new(0)).collect());
     let _ = VAR_NAMES.set(var_names);
     if let Ok(mut values) = STRING_VAR_VALUES.write() {
         *values = vec![String::new(); string_var_names.len()];
     }
     let _ = STRING_VAR_NAMES.set(string_var_names);
+    if let Ok(mut states) = TRIGGER_STATES.write() {
+        *states = vec![0; trigger_names.len()];
+    }
+    let _ = TRIGGER_NAMES.set(trigger_names);
     let _ = HOTKEY.set(hotkey_val);
 
     // GUI Thread
@@ -181,7 +95,9 @@ pub unsafe extern "stdcall" fn PluginStart(_a_owner: uintptr_t) {
     });
 
     thread::spawn(move || {
-        let mut last_values = vec![0u32; SHARED_ARRAY_SIZE];
+        let shared: &[AtomicU32] = SHARED_ARRAY.get().map_or(&[], |a| a);
+        let mut last_values = vec![0u32; shared.len()];
+        let mut out_of_range_logged = false;
         let now_date = chrono::Local::now().format("%Y-%m-%d").to_string();
         let log_file_path = format!("omsilogger_{}.txt", now_date);
 
@@ -198,11 +114,37 @@ pub unsafe extern "stdcall" fn PluginStart(_a_owner: uintptr_t) {
                 version
             );
             let _ = file.write_all(start_msg.as_bytes());
+            for diagnostic in &diagnostics {
+                let _ = file.write_all(format!("{}\n", diagnostic).as_bytes());
+            }
+        }
+        if let Ok(mut messages) = LOG_MESSAGES.lock() {
+            messages.extend(diagnostics.iter().cloned());
         }
 
         loop {
-            for i in 0..SHARED_ARRAY_SIZE {
-                let current_val = SHARED_ARRAY[i].load(Relaxed);
+            let out_of_range = OUT_OF_RANGE_INDEX.load(Relaxed);
+            if !out_of_range_logged && out_of_range != usize::MAX {
+                let log_line = format!(
+                    "{}: OMSI accessed variable index {} which is beyond the lists in the opl, check the count lines\n",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    out_of_range
+                );
+                if let Ok(mut file) = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&log_file_path)
+                {
+                    let _ = file.write_all(log_line.as_bytes());
+                }
+                if let Ok(mut messages) = LOG_MESSAGES.lock() {
+                    messages.push(log_line);
+                }
+                out_of_range_logged = true;
+            }
+
+            for (i, value) in shared.iter().enumerate() {
+                let current_val = value.load(Relaxed);
                 if current_val != last_values[i] {
                     let var_name = VAR_NAMES
                         .get()
@@ -241,6 +183,15 @@ pub unsafe extern "stdcall" fn PluginStart(_a_owner: uintptr_t) {
     });
 }
 
+fn store_value(index: usize, value: f32) {
+    match SHARED_ARRAY.get().and_then(|a| a.get(index)) {
+        Some(slot) => slot.store(value.abs().round() as u32, Relaxed),
+        None => {
+            let _ = OUT_OF_RANGE_INDEX.compare_exchange(usize::MAX, index, Relaxed, Relaxed);
+        }
+    }
+}
+
 #[allow(non_snake_case, unused_variables)]
 #[unsafe(export_name = "AccessVariable")]
 pub unsafe extern "stdcall" fn AccessVariable(
@@ -250,12 +201,8 @@ pub unsafe extern "stdcall" fn AccessVariable(
 ) {
     let offset = SYSTEM_VAR_COUNT.load(Relaxed) as usize;
     let index = variableIndex as usize + offset;
-    if index < SHARED_ARRAY_SIZE {
-        unsafe {
-            let f = *value;
-            let a = f.abs().round() as u32;
-            SHARED_ARRAY[index].store(a, Relaxed);
-        }
+    unsafe {
+        store_value(index, *value);
     }
 }
 
@@ -350,18 +297,72 @@ pub unsafe extern "stdcall" fn AccessSystemVariable(
     writeValue: *const bool,
 ) {
     let index = variableIndex as usize;
-    if index < SHARED_ARRAY_SIZE {
-        unsafe {
-            let f = *value;
-            let a = f.abs().round() as u32;
-            SHARED_ARRAY[index].store(a, Relaxed);
-        }
+    unsafe {
+        store_value(index, *value);
     }
 }
 
 #[allow(non_snake_case, unused_variables)]
 #[unsafe(export_name = "AccessTrigger")]
-pub unsafe extern "stdcall" fn AccessTrigger(variableIndex: u16, triggerScript: *const bool) {}
+pub unsafe extern "stdcall" fn AccessTrigger(variableIndex: u16, triggerScript: *const bool) {
+    if triggerScript.is_null() {
+        return;
+    }
+
+    let index = variableIndex as usize;
+    let state = if unsafe { *triggerScript } { 2 } else { 1 };
+
+    // only take the write lock when a trigger shows up or changes
+    match TRIGGER_STATES.read() {
+        Ok(states) if states.get(index).is_some_and(|s| *s != state) => {}
+        _ => return,
+    }
+
+    let old_state = match TRIGGER_STATES.write() {
+        Ok(mut states) => std::mem::replace(&mut states[index], state),
+        Err(_) => return,
+    };
+
+    let trigger_name = TRIGGER_NAMES
+        .get()
+        .and_then(|names| names.get(index).cloned())
+        .unwrap_or_else(|| format!("TriggerIndex_{}", index));
+
+    let now = chrono::Local::now();
+    let log_line = if old_state == 0 {
+        format!(
+            "{}: trigger {} exposed, active: {}\n",
+            now.format("%Y-%m-%d %H:%M:%S"),
+            trigger_name,
+            state == 2
+        )
+    } else {
+        format!(
+            "{}: trigger {} active: {}\n",
+            now.format("%Y-%m-%d %H:%M:%S"),
+            trigger_name,
+            state == 2
+        )
+    };
+
+    let now_date = now.format("%Y-%m-%d").to_string();
+    let log_file_path = format!("omsilogger_{}.txt", now_date);
+
+    if let Ok(mut file) = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_file_path)
+    {
+        let _ = file.write_all(log_line.as_bytes());
+    }
+
+    if let Ok(mut messages) = LOG_MESSAGES.lock() {
+        messages.push(log_line.clone());
+        if messages.len() > 100 {
+            messages.remove(0);
+        }
+    }
+}
 
 #[allow(non_snake_case)]
 #[unsafe(export_name = "PluginFinalize")]
@@ -0,0 +1,578 @@
+//! Recording of the values OMSI hands over, and the replay of a recording without OMSI.
+//!
+//! A recording is a text file, one line per changed value:
+//!
+//! ```text
+//! # omsi2komsi recording, started 2026-10-18 14:30:00
+//! name y0 timehour
+//! name v0 elec_busbar_main
+//! name s0 ibis_busstop_name
+//! 0 s0 Hauptbahnhof
+//! 16 y0 14
+//! 16 v0 1
+//! ```
+//!
+//! `name` lines give the variable names, `y`, `v` and `s` are the `[systemvarlist]`, `[varlist]` and
+//! `[stringvarlist]` indices. The other lines are the milliseconds since the start, the variable and
+//! the new value. Texts escape `\`, line feeds and carriage returns with a backslash.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError, sync_channel};
+use std::sync::{Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{
+    AccessStringVariable, AccessSystemVariable, AccessTrigger, AccessVariable, PluginFinalize,
+    PluginStart, REPLAY, STRING_VAR_NAMES, STRING_VAR_VALUES, SYSTEM_VAR_COUNT, TRIGGER_NAMES,
+    VAR_NAMES, log_message,
+};
+
+pub const SYSTEM: u8 = b'y';
+pub const VARIABLE: u8 = b'v';
+const STRING: u8 = b's';
+
+// values waiting for the writer thread, OMSI hands over a few hundred per frame
+const QUEUE_LEN: usize = 8192;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// OMSI calls the variables once per frame, the replay does the same at about 50 fps
+const FRAME: Duration = Duration::from_millis(20);
+// no value has these bits, so the first call of every variable is recorded
+const UNSET: u32 = u32::MAX;
+
+enum Event {
+    Value {
+        ms: u64,
+        kind: u8,
+        index: u16,
+        value: f32,
+    },
+    Text {
+        ms: u64,
+        index: u16,
+        text: String,
+    },
+}
+
+struct Recorder {
+    start: Instant,
+    queue: SyncSender<Event>,
+    /// the last recorded value of every variable as bits, system variables first
+    last: Vec<AtomicU32>,
+    overflow_logged: AtomicBool,
+    writer: JoinHandle<()>,
+}
+
+// checked first, so OMSI's calls do not take the lock while nothing is recorded
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static RECORDER: RwLock<Option<Recorder>> = RwLock::new(None);
+static SWITCHING: Mutex<()> = Mutex::new(());
+
+/// Starts recording to a new file next to omsi2komsi.opl.
+pub fn start(
+    config_path: &str,
+    var_names: &[String],
+    system_var_count: usize,
+    string_var_names: &[String],
+) {
+    let _guard = SWITCHING.lock();
+    if RECORDING.load(Relaxed) {
+        return;
+    }
+    let now = chrono::Local::now();
+    let path = Path::new(config_path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(format!(
+            "omsi2komsi_{}.rec",
+            now.format("%Y-%m-%d_%H-%M-%S")
+        ));
+
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            log_message(format!(
+                "Could not create recording {}: {}",
+                path.display(),
+                e
+            ));
+            return;
+        }
+    };
+    let mut header = format!(
+        "# omsi2komsi recording, started {}\n",
+        now.format("%Y-%m-%d %H:%M:%S")
+    );
+    for (i, name) in var_names.iter().enumerate() {
+        if i < system_var_count {
+            header.push_str(&format!("name y{} {}\n", i, name));
+        } else {
+            header.push_str(&format!("name v{} {}\n", i - system_var_count, name));
+        }
+    }
+    for (i, name) in string_var_names.iter().enumerate() {
+        header.push_str(&format!("name s{} {}\n", i, name));
+    }
+
+    let (queue, receiver) = sync_channel(QUEUE_LEN);
+    // the texts OMSI has already handed over are not sent again until they change
+    if let Ok(values) = STRING_VAR_VALUES.read() {
+        for (index, text) in values.iter().enumerate() {
+            if !text.is_empty() {
+                let _ = queue.try_send(Event::Text {
+                    ms: 0,
+                    index: index as u16,
+                    text: text.clone(),
+                });
+            }
+        }
+    }
+    let thread_path = path.clone();
+    let writer = thread::spawn(move || write_recording(file, header, thread_path, receiver));
+
+    if let Ok(mut recorder) = RECORDER.write() {
+        *recorder = Some(Recorder {
+            start: Instant::now(),
+            queue,
+            last: (0..var_names.len())
+                .map(|_| AtomicU32::new(UNSET))
+                .collect(),
+            overflow_logged: AtomicBool::new(false),
+            writer,
+        });
+        RECORDING.store(true, Relaxed);
+        log_message(format!("Recording to {}", path.display()));
+    }
+}
+
+/// Stops recording and waits until the file is written.
+pub fn stop() {
+    let _guard = SWITCHING.lock();
+    RECORDING.store(false, Relaxed);
+    let recorder = RECORDER.write().ok().and_then(|mut r| r.take());
+    if let Some(recorder) = recorder {
+        // the writer thread ends when the queue is closed
+        drop(recorder.queue);
+        let _ = recorder.writer.join();
+        log_message("Recording stopped".to_string());
+    }
+}
+
+/// Records a variable value if it changed, `index` counts the system variables first.
+pub fn variable(kind: u8, variable_index: u16, index: usize, value: f32) {
+    if !RECORDING.load(Relaxed) {
+        return;
+    }
+    let Ok(recorder) = RECORDER.read() else {
+        return;
+    };
+    let Some(recorder) = recorder.as_ref() else {
+        return;
+    };
+    let Some(last) = recorder.last.get(index) else {
+        return;
+    };
+    if last.swap(value.to_bits(), Relaxed) != value.to_bits() {
+        recorder.send(Event::Value {
+            ms: recorder.start.elapsed().as_millis() as u64,
+            kind,
+            index: variable_index,
+            value,
+        });
+    }
+}
+
+/// Records a changed string variable.
+pub fn text(index: usize, text: &str) {
+    if !RECORDING.load(Relaxed) {
+        return;
+    }
+    if let Ok(recorder) = RECORDER.read()
+        && let Some(recorder) = recorder.as_ref()
+    {
+        recorder.send(Event::Text {
+            ms: recorder.start.elapsed().as_millis() as u64,
+            index: index as u16,
+            text: text.to_string(),
+        });
+    }
+}
+
+impl Recorder {
+    fn send(&self, event: Event) {
+        // OMSI must never wait for the disk, a full queue loses values instead
+        if let Err(TrySendError::Full(_)) = self.queue.try_send(event)
+            && !self.overflow_logged.swap(true, Relaxed)
+        {
+            log_message("Recording cannot keep up, values are lost".to_string());
+        }
+    }
+}
+
+fn write_recording(file: File, header: String, path: PathBuf, queue: Receiver<Event>) {
+    let mut out = BufWriter::new(file);
+    let mut result = out.write_all(header.as_bytes());
+    while result.is_ok() {
+        result = match queue.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Event::Value {
+                ms,
+                kind,
+                index,
+                value,
+            }) => writeln!(out, "{} {}{} {}", ms, kind as char, index, value),
+            Ok(Event::Text { ms, index, text }) => {
+                writeln!(out, "{} s{} {}", ms, index, escape(&text))
+            }
+            // a crash of OMSI loses at most the last second
+            Err(RecvTimeoutError::Timeout) => out.flush(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+    }
+    if let Err(e) = result.and_then(|_| out.flush()) {
+        log_message(format!(
+            "Could not write recording {}: {}",
+            path.display(),
+            e
+        ));
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[derive(PartialEq, Debug)]
+enum Line {
+    Name {
+        kind: u8,
+        index: u16,
+        name: String,
+    },
+    Value {
+        ms: u64,
+        kind: u8,
+        index: u16,
+        value: f32,
+    },
+    Text {
+        ms: u64,
+        index: u16,
+        text: String,
+    },
+}
+
+/// Parses one line of a recording, `None` for malformed lines.
+fn parse_line(line: &str) -> Option<Line> {
+    let mut parts = line.splitn(3, ' ');
+    let first = parts.next()?;
+    let variable = parts.next()?;
+    // an empty text may have lost its trailing space
+    let rest = parts.next().unwrap_or("");
+    let kind = *variable.as_bytes().first()?;
+    if ![SYSTEM, VARIABLE, STRING].contains(&kind) {
+        return None;
+    }
+    let index = variable[1..].parse().ok()?;
+
+    if first == "name" {
+        return Some(Line::Name {
+            kind,
+            index,
+            name: rest.trim().to_lowercase(),
+        });
+    }
+    let ms = first.parse().ok()?;
+    if kind == STRING {
+        Some(Line::Text {
+            ms,
+            index,
+            text: unescape(rest),
+        })
+    } else {
+        Some(Line::Value {
+            ms,
+            kind,
+            index,
+            value: rest.trim().parse().ok()?,
+        })
+    }
+}
+
+/// The variables of omsi2komsi.opl and the values the replay hands over to the plugin.
+struct ReplayState {
+    var_names: Vec<String>,
+    system_var_count: usize,
+    string_var_names: Vec<String>,
+    /// system variables first, as in `VAR_NAMES`
+    values: Vec<f32>,
+    triggers: Vec<bool>,
+    /// recorded variable to the index in `values` or `string_var_names`
+    targets: HashMap<(u8, u16), usize>,
+    /// set when a repeat reads the names again
+    names_known: bool,
+}
+
+impl ReplayState {
+    fn new() -> ReplayState {
+        let var_names = VAR_NAMES.read().map(|n| n.clone()).unwrap_or_default();
+        let trigger_count = TRIGGER_NAMES.read().map(|n| n.len()).unwrap_or_default();
+        ReplayState {
+            values: vec![0.0; var_names.len()],
+            var_names,
+            system_var_count: SYSTEM_VAR_COUNT.load(Relaxed),
+            string_var_names: STRING_VAR_NAMES
+                .read()
+                .map(|n| n.clone())
+                .unwrap_or_default(),
+            triggers: vec![false; trigger_count],
+            targets: HashMap::new(),
+            names_known: false,
+        }
+    }
+
+    /// Finds a recorded variable in omsi2komsi.opl, the indices may differ from the recording.
+    fn add_name(&mut self, kind: u8, index: u16, name: &str) {
+        let target = match kind {
+            SYSTEM => self.var_names[..self.system_var_count]
+                .iter()
+                .position(|n| n == name),
+            VARIABLE => self.var_names[self.system_var_count..]
+                .iter()
+                .position(|n| n == name)
+                .map(|i| i + self.system_var_count),
+            _ => self.string_var_names.iter().position(|n| n == name),
+        };
+        match target {
+            Some(target) => {
+                self.targets.insert((kind, index), target);
+            }
+            None => log_message(format!(
+                "'{}' is not in omsi2komsi.opl, its values are skipped",
+                name
+            )),
+        }
+    }
+
+    fn apply(&mut self, line: Line) {
+        match line {
+            Line::Name { kind, index, name } => {
+                if !self.names_known {
+                    self.add_name(kind, index, &name);
+                }
+            }
+            Line::Value {
+                kind, index, value, ..
+            } => {
+                if let Some(&target) = self.targets.get(&(kind, index)) {
+                    self.values[target] = value;
+                }
+            }
+            Line::Text { index, text, .. } => {
+                if let Some(&target) = self.targets.get(&(STRING, index)) {
+                    let wide: Vec<u16> = text.encode_utf16().chain([0]).collect();
+                    let mut write = false;
+                    unsafe { AccessStringVariable(target as u16, wide.as_ptr(), &mut write) };
+                }
+            }
+        }
+    }
+
+    /// Calls every variable and trigger like OMSI does once per frame.
+    fn frame(&mut self) {
+        for (i, value) in self.values.iter_mut().enumerate() {
+            let mut write = false;
+            let before = *value;
+            unsafe {
+                if i < self.system_var_count {
+                    AccessSystemVariable(i as u16, value, &mut write);
+                } else {
+                    AccessVariable((i - self.system_var_count) as u16, value, &mut write);
+                }
+            }
+            // OMSI would take the value over, until the recording changes it again
+            if write {
+                log_message(format!(
+                    "Replay: {} set from {} to {}",
+                    self.var_names[i], before, value
+                ));
+            } else {
+                *value = before;
+            }
+        }
+        for (i, active) in self.triggers.iter_mut().enumerate() {
+            unsafe { AccessTrigger(i as u16, active) };
+        }
+    }
+}
+
+fn parsed_lines(path: &Path) -> io::Result<impl Iterator<Item = Line>> {
+    let reader = BufReader::new(File::open(path)?);
+    let path = path.display().to_string();
+    Ok(reader
+        .lines()
+        .map_while(Result::ok)
+        .enumerate()
+        .filter_map(move |(i, line)| {
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let parsed = parse_line(&line);
+            if parsed.is_none() {
+                log_message(format!("{}: line {} is malformed, skipped", path, i + 1));
+            }
+            parsed
+        }))
+}
+
+/// Plays a recording back through the plugin as if OMSI was running, with the settings and outputs
+/// of omsi2komsi.opl in the current directory.
+///
+/// `speed` 2.0 plays twice as fast, `repeat` starts over at the end instead of returning.
+pub fn replay(path: &Path, speed: f64, repeat: bool) -> io::Result<()> {
+    // fails before the plugin starts its outputs
+    let mut lines = parsed_lines(path)?;
+    // a replay neither records itself again nor adds to omsi2komsi.state
+    REPLAY.store(true, Relaxed);
+    unsafe { PluginStart(0) };
+    let mut state = ReplayState::new();
+    log_message(format!("Replaying {}", path.display()));
+
+    loop {
+        let start = Instant::now();
+        let mut next = lines.next();
+        while next.is_some() {
+            let now = start.elapsed().as_secs_f64() * 1000.0 * speed;
+            while let Some(line) = next.take_if(|line| match line {
+                Line::Name { .. } => true,
+                Line::Value { ms, .. } | Line::Text { ms, .. } => *ms as f64 <= now,
+            }) {
+                state.apply(line);
+                next = lines.next();
+            }
+            state.frame();
+            thread::sleep(FRAME);
+        }
+        if !repeat {
+            break;
+        }
+        log_message("Replay finished, starting over".to_string());
+        state.names_known = true;
+        lines = parsed_lines(path)?;
+    }
+
+    // the send loop needs a moment for the last values
+    for _ in 0..25 {
+        state.frame();
+        thread::sleep(FRAME);
+    }
+    log_message("Replay finished".to_string());
+    unsafe { PluginFinalize() };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texts_round_trip() {
+        for text in [
+            "Hauptbahnhof",
+            "C:\\Busse\\MAN",
+            "two\nlines\r\n",
+            "  leading spaces",
+            "trailing \\",
+            "\\n is no line feed",
+            "",
+        ] {
+            let line = format!("{} s{} {}", 1234, 2, escape(text));
+            assert!(!line.contains('\n'), "{:?}", line);
+            assert_eq!(
+                parse_line(&line),
+                Some(Line::Text {
+                    ms: 1234,
+                    index: 2,
+                    text: text.to_string(),
+                })
+            );
+        }
+        // an editor may have stripped the space of an empty text
+        assert_eq!(
+            parse_line("16 s0"),
+            Some(Line::Text {
+                ms: 16,
+                index: 0,
+                text: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn values_round_trip() {
+        for value in [0.0, 14.0, -0.5, 0.1, 1234.567, f32::MAX] {
+            let line = format!("{} {}{} {}", 16, VARIABLE as char, 7, value);
+            assert_eq!(
+                parse_line(&line),
+                Some(Line::Value {
+                    ms: 16,
+                    kind: VARIABLE,
+                    index: 7,
+                    value,
+                })
+            );
+        }
+        assert_eq!(
+            parse_line("name y0 TimeHour"),
+            Some(Line::Name {
+                kind: SYSTEM,
+                index: 0,
+                name: "timehour".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in [
+            "",
+            "16",
+            "16 y0",
+            "16 y0 one",
+            "16 x0 1",
+            "16 y 1",
+            "16 yz 1",
+            "16 y-1 1",
+            "-16 y0 1",
+            "later y0 1",
+            "16 ü0 1",
+            "name x0 timehour",
+        ] {
+            assert_eq!(parse_line(line), None, "{:?}", line);
+        }
+    }
+}
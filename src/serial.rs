@@ -0,0 +1,339 @@
+//! One writer thread per serial port, so a slow or missing device never stalls the other ports,
+//! the send loop or the OMSI callbacks.
+
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use komsi::komsi::KomsiCommand;
+use omsi_opl::{FlowControl, Parity, PortSelector, PortSettings};
+
+use crate::{INPUT_MAPPINGS, SERIAL_PORT_ENABLED, filter_komsi, log_message, read_serial_input};
+
+// KOMSI lines waiting for a port, further lines are dropped until the port catches up
+const QUEUE_LEN: usize = 32;
+// first and longest wait before the next attempt to open a missing port
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+// how often a worker looks for input when there is nothing to write
+const POLL: Duration = Duration::from_millis(20);
+
+/// Names of the ports the workers have opened, USB selectors skip them.
+static OPEN_PORTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// A serial port with the KOMSI letters it receives.
+pub struct PortConfig {
+    pub settings: PortSettings,
+    /// `None` sends all fields
+    pub letters: Option<Vec<u8>>,
+}
+
+impl PortConfig {
+    /// Returns the part of `buf` this port receives.
+    pub fn filter(&self, buf: &[u8]) -> Vec<u8> {
+        match &self.letters {
+            Some(letters) => filter_komsi(buf, letters),
+            None => buf.to_vec(),
+        }
+    }
+}
+
+fn open(s: &PortSettings, port_name: &str) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+    let data_bits = match s.data_bits {
+        5 => serialport::DataBits::Five,
+        6 => serialport::DataBits::Six,
+        7 => serialport::DataBits::Seven,
+        _ => serialport::DataBits::Eight,
+    };
+    let parity = match s.parity {
+        Parity::None => serialport::Parity::None,
+        Parity::Odd => serialport::Parity::Odd,
+        Parity::Even => serialport::Parity::Even,
+    };
+    let stop_bits = match s.stop_bits {
+        2 => serialport::StopBits::Two,
+        _ => serialport::StopBits::One,
+    };
+    let flow_control = match s.flow_control {
+        FlowControl::None => serialport::FlowControl::None,
+        FlowControl::Software => serialport::FlowControl::Software,
+        FlowControl::Hardware => serialport::FlowControl::Hardware,
+    };
+    serialport::new(port_name, s.baudrate)
+        .data_bits(data_bits)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .flow_control(flow_control)
+        .timeout(Duration::from_millis(s.timeout_ms))
+        .open()
+}
+
+/// Returns the name of the serial port to open for a `portname` value.
+///
+/// USB selectors are looked up in the ports Windows currently knows, so a device that got a new COM
+/// number is found again. Ports in `in_use` are left for the other `portname` entries.
+fn resolve_port(portname: &str, in_use: &[String]) -> Option<String> {
+    let selector = PortSelector::parse(portname)?;
+    if let PortSelector::Name(name) = selector {
+        return Some(name);
+    }
+    let ports = serialport::available_ports().ok()?;
+    ports
+        .into_iter()
+        .filter(|p| !in_use.iter().any(|u| u.eq_ignore_ascii_case(&p.port_name)))
+        .find(|p| match &p.port_type {
+            serialport::SerialPortType::UsbPort(usb) => selector.matches_usb(
+                usb.vid,
+                usb.pid,
+                usb.serial_number.as_deref(),
+                usb.product.as_deref(),
+            ),
+            _ => false,
+        })
+        .map(|p| p.port_name)
+}
+
+/// Resolves and opens the port and sends the init string, the error is the message to log.
+fn connect(
+    s: &PortSettings,
+    needs_full_state: &AtomicBool,
+) -> Result<(String, Box<dyn serialport::SerialPort>), String> {
+    let in_use = OPEN_PORTS.lock().map(|p| p.clone()).unwrap_or_default();
+    let Some(port_name) = resolve_port(&s.portname, &in_use) else {
+        return Err(format!("No serial port found for {}", s.portname));
+    };
+    let mut port = open(s, &port_name)
+        .map_err(|e| format!("Failed to open serial port {}: {}", port_name, e))?;
+
+    if port_name == s.portname {
+        log_message(format!("Serial port {} opened successfully", port_name));
+    } else {
+        log_message(format!(
+            "Serial port {} ({}) opened successfully",
+            port_name, s.portname
+        ));
+    }
+
+    // send SimulatorType:OMSI
+    let mut init_buf = KomsiCommand::build(&KomsiCommand::SimulatorType(0));
+    init_buf.extend_from_slice(&KomsiCommand::build_eol());
+    if let Err(e) = port.write_all(&init_buf) {
+        log_message(format!(
+            "Failed to send init string to {}: {}",
+            port_name, e
+        ));
+    }
+    // a new or reset device knows nothing yet, so the send loop sends it the complete state next
+    needs_full_state.store(true, Relaxed);
+
+    if let Ok(mut open_ports) = OPEN_PORTS.lock() {
+        open_ports.push(port_name.clone());
+    }
+    Ok((port_name, port))
+}
+
+fn close(port: &mut Option<Box<dyn serialport::SerialPort>>, port_name: &str) {
+    if port.take().is_some()
+        && let Ok(mut open_ports) = OPEN_PORTS.lock()
+    {
+        open_ports.retain(|p| p != port_name);
+    }
+}
+
+/// Owns one serial port: opens it with backoff, writes the queued lines and reads the input.
+fn run(settings: PortSettings, queue: Receiver<Vec<u8>>, needs_full_state: Arc<AtomicBool>) {
+    let mut port: Option<Box<dyn serialport::SerialPort>> = None;
+    let mut port_name = String::new();
+    let mut input_buf = Vec::new();
+    let mut backoff = BACKOFF_MIN;
+    let mut next_attempt = Instant::now();
+    // logged once until the port is open again
+    let mut missing = false;
+
+    loop {
+        let buf = match queue.recv_timeout(POLL) {
+            Ok(buf) => Some(buf),
+            Err(RecvTimeoutError::Timeout) => None,
+            // the config changed or the plugin is unloaded
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if !SERIAL_PORT_ENABLED.load(Relaxed) {
+            close(&mut port, &port_name);
+            continue;
+        }
+
+        if port.is_none() && Instant::now() >= next_attempt {
+            match connect(&settings, &needs_full_state) {
+                Ok((name, p)) => {
+                    port_name = name;
+                    port = Some(p);
+                    missing = false;
+                    backoff = BACKOFF_MIN;
+                    input_buf.clear();
+                }
+                Err(msg) => {
+                    if !missing {
+                        log_message(format!("{}, retrying in the background", msg));
+                        missing = true;
+                    }
+                    next_attempt = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(BACKOFF_MAX);
+                }
+            }
+        }
+
+        // lines for a missing port are dropped, the complete state after opening replaces them
+        let Some(p) = port.as_mut() else {
+            continue;
+        };
+
+        if let Some(buf) = buf
+            && let Err(e) = p.write_all(&buf)
+        {
+            log_message(format!(
+                "Serial write error on {}: {}. Closing port.",
+                port_name, e
+            ));
+            close(&mut port, &port_name);
+            continue;
+        }
+
+        if let Ok(input_mappings) = INPUT_MAPPINGS.read()
+            && !input_mappings.is_empty()
+            && let Err(e) = read_serial_input(p, &mut input_buf, &input_mappings)
+        {
+            log_message(format!(
+                "Serial read error on {}: {}. Closing port.",
+                port_name, e
+            ));
+            close(&mut port, &port_name);
+        }
+    }
+
+    close(&mut port, &port_name);
+}
+
+/// The send loop's handle to a port thread, dropping it closes the port.
+pub struct PortWorker {
+    portname: String,
+    queue: SyncSender<Vec<u8>>,
+    /// set by the port thread when the port opens and by `send` when lines were lost
+    needs_full_state: Arc<AtomicBool>,
+    /// set while the queue is full, so the overflow is logged once
+    overflow: bool,
+}
+
+impl PortWorker {
+    pub fn spawn(settings: &PortSettings) -> PortWorker {
+        let (queue, receiver) = sync_channel(QUEUE_LEN);
+        let needs_full_state = Arc::new(AtomicBool::new(false));
+        let thread_settings = settings.clone();
+        let thread_flag = needs_full_state.clone();
+        thread::spawn(move || run(thread_settings, receiver, thread_flag));
+        PortWorker {
+            portname: settings.portname.clone(),
+            queue,
+            needs_full_state,
+            overflow: false,
+        }
+    }
+
+    /// Queues the part of `cmdbuf` this port receives without waiting for the port.
+    ///
+    /// A port that has just been opened or has lost lines gets `full_state` instead, the other
+    /// outputs are not affected.
+    pub fn send(&mut self, port: &PortConfig, cmdbuf: &[u8], full_state: &dyn Fn() -> Vec<u8>) {
+        let buf = if self.needs_full_state.swap(false, Relaxed) {
+            port.filter(&full_state())
+        } else {
+            port.filter(cmdbuf)
+        };
+        if buf.is_empty() {
+            return;
+        }
+        match self.queue.try_send(buf) {
+            Ok(()) => self.overflow = false,
+            Err(TrySendError::Full(_)) => {
+                if !self.overflow {
+                    log_message(format!(
+                        "Serial port {} cannot keep up, dropping commands",
+                        self.portname
+                    ));
+                    self.overflow = true;
+                }
+                // sent once the queue has room again, it replaces the lost lines
+                self.needs_full_state.store(true, Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> PortSettings {
+        omsi_opl::Opl::parse("[omsi2komsi]\nportname = com9\n").serial_ports()[0].clone()
+    }
+
+    fn worker() -> (PortWorker, Receiver<Vec<u8>>) {
+        let (queue, receiver) = sync_channel(QUEUE_LEN);
+        let worker = PortWorker {
+            portname: "com9".to_string(),
+            queue,
+            needs_full_state: Arc::new(AtomicBool::new(false)),
+            overflow: false,
+        };
+        (worker, receiver)
+    }
+
+    #[test]
+    fn full_state_only_for_this_port() {
+        let (mut worker, receiver) = worker();
+        let port = PortConfig {
+            settings: settings(),
+            letters: Some(vec![b'A', b'y']),
+        };
+        let full_state = || b"A1B1y50\n".to_vec();
+
+        // the port thread opened the port
+        worker.needs_full_state.store(true, Relaxed);
+        worker.send(&port, b"y51\n", &full_state);
+        assert_eq!(receiver.try_recv().unwrap(), b"A1y50\n");
+        worker.send(&port, b"B0y52\n", &full_state);
+        assert_eq!(receiver.try_recv().unwrap(), b"y52\n");
+        assert!(!worker.needs_full_state.load(Relaxed));
+    }
+
+    #[test]
+    fn full_state_after_overflow() {
+        let (mut worker, receiver) = worker();
+        let port = PortConfig {
+            settings: settings(),
+            letters: None,
+        };
+        let full_state = || b"A1y50\n".to_vec();
+
+        for _ in 0..QUEUE_LEN {
+            worker.send(&port, b"y1\n", &full_state);
+        }
+        worker.send(&port, b"y2\n", &full_state);
+        assert!(worker.overflow);
+        // still full, the complete state waits for room
+        worker.send(&port, b"y3\n", &full_state);
+        assert!(worker.needs_full_state.load(Relaxed));
+
+        while receiver.try_recv().is_ok() {}
+        worker.send(&port, b"y4\n", &full_state);
+        assert_eq!(receiver.try_recv().unwrap(), b"A1y50\n");
+        assert!(!worker.overflow);
+        worker.send(&port, b"y5\n", &full_state);
+        assert_eq!(receiver.try_recv().unwrap(), b"y5\n");
+    }
+}
@@ -0,0 +1,159 @@
+//! TCP server for KOMSI clients like dashboard apps, recorders or test harnesses.
+//!
+//! Every client gets its own writer thread, so a slow or vanished client never stalls the send loop.
+
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread;
+use std::time::Duration;
+
+use crate::log_message;
+
+// KOMSI lines waiting for a client, a client that falls further behind is disconnected
+const QUEUE_LEN: usize = 64;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Client {
+    peer: String,
+    queue: SyncSender<Vec<u8>>,
+}
+
+/// The `tcplisten` server of `[omsi2komsi]`, dropping it stops listening and disconnects the clients.
+pub struct TcpOutput {
+    listener: TcpListener,
+    clients: Vec<Client>,
+}
+
+impl TcpOutput {
+    pub fn new(listen: &str) -> Option<TcpOutput> {
+        // non-blocking, new clients are picked up by the send loop without waiting
+        match TcpListener::bind(listen).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        }) {
+            Ok(listener) => {
+                log_message(format!("TCP server listening on {}", listen));
+                Some(TcpOutput {
+                    listener,
+                    clients: Vec::new(),
+                })
+            }
+            Err(e) => {
+                log_message(format!("Could not start TCP server on {}: {}", listen, e));
+                None
+            }
+        }
+    }
+
+    /// Sends `buf` to the connected clients, new clients get `full_state` instead.
+    pub fn send(&mut self, buf: &[u8], full_state: &dyn Fn() -> Vec<u8>) {
+        self.clients.retain(|client| {
+            if buf.is_empty() {
+                return true;
+            }
+            match client.queue.try_send(buf.to_vec()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log_message(format!(
+                        "TCP client {} cannot keep up, disconnecting",
+                        client.peer
+                    ));
+                    false
+                }
+                // the writer thread has logged the disconnect
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+
+        while let Ok((stream, addr)) = self.listener.accept() {
+            let peer = addr.to_string();
+            log_message(format!("TCP client {} connected", peer));
+            let (queue, receiver) = sync_channel(QUEUE_LEN);
+            // the first line cannot fail, the queue is still empty
+            let _ = queue.try_send(full_state());
+            let thread_peer = peer.clone();
+            thread::spawn(move || run(stream, thread_peer, receiver));
+            self.clients.push(Client { peer, queue });
+        }
+    }
+}
+
+/// Writes the queued lines to one client until it disconnects or is dropped.
+fn run(mut stream: TcpStream, peer: String, queue: Receiver<Vec<u8>>) {
+    // accepted sockets inherit the non-blocking mode of the listener
+    if let Err(e) = stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+        .and_then(|_| stream.set_nodelay(true))
+    {
+        log_message(format!("TCP client {}: {}", peer, e));
+        return;
+    }
+    while let Ok(buf) = queue.recv() {
+        if let Err(e) = stream.write_all(&buf) {
+            log_message(format!("TCP client {} disconnected: {}", peer, e));
+            return;
+        }
+    }
+    log_message(format!("TCP client {} disconnected", peer));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Instant;
+
+    fn read_exact(client: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn full_state_then_changes() {
+        let mut output = TcpOutput::new("127.0.0.1:0").unwrap();
+        let addr = output.listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        let start = Instant::now();
+        while output.clients.is_empty() && start.elapsed() < Duration::from_secs(2) {
+            output.send(b"", &|| b"A1B0y14\n".to_vec());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(output.clients.len(), 1);
+        assert_eq!(read_exact(&mut client, 8), b"A1B0y14\n");
+
+        // the client is known now, only the changes follow
+        output.send(b"B1\n", &|| panic!("full state for a known client"));
+        output.send(b"y15\n", &|| panic!("full state for a known client"));
+        assert_eq!(read_exact(&mut client, 7), b"B1\ny15\n");
+    }
+
+    #[test]
+    fn closed_client_is_dropped() {
+        let mut output = TcpOutput::new("127.0.0.1:0").unwrap();
+        let addr = output.listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        while output.clients.is_empty() && start.elapsed() < Duration::from_secs(2) {
+            output.send(b"", &|| b"A1\n".to_vec());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(output.clients.len(), 1);
+        drop(client);
+
+        let start = Instant::now();
+        while !output.clients.is_empty() && start.elapsed() < Duration::from_secs(2) {
+            let round = Instant::now();
+            output.send(b"y14\n", &|| b"A1\n".to_vec());
+            assert!(round.elapsed() < Duration::from_millis(50));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(output.clients.is_empty());
+    }
+}
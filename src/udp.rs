@@ -0,0 +1,180 @@
+//! Sends the KOMSI lines as UDP datagrams, e.g. to an ESP32 dashboard on Wi-Fi or a second PC.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{Receiver, TryRecvError, sync_channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::log_message;
+
+// a failed name lookup is not repeated on every round of the send loop
+const RESOLVE_RETRY: Duration = Duration::from_secs(5);
+
+struct Target {
+    /// `host:port` as written in `udptargets`
+    name: String,
+    addr: Option<SocketAddr>,
+    /// the running lookup, a name that does not resolve can take seconds
+    lookup: Option<Receiver<Option<SocketAddr>>>,
+    /// no lookup before this time after a failed one
+    retry_at: Option<Instant>,
+    /// logged once until a datagram gets through again
+    failing: bool,
+}
+
+impl Target {
+    /// Returns the address, the lookup runs on its own thread and is picked up by a later call.
+    fn addr(&mut self) -> Option<SocketAddr> {
+        if self.addr.is_some() {
+            return self.addr;
+        }
+        if let Some(lookup) = &self.lookup {
+            match lookup.try_recv() {
+                Ok(Some(addr)) => {
+                    self.lookup = None;
+                    self.addr = Some(addr);
+                    return self.addr;
+                }
+                Err(TryRecvError::Empty) => return None,
+                Ok(None) | Err(TryRecvError::Disconnected) => {
+                    self.lookup = None;
+                    self.fail("host not found");
+                    self.retry_at = Some(Instant::now() + RESOLVE_RETRY);
+                    return None;
+                }
+            }
+        }
+        if self.retry_at.is_none_or(|t| Instant::now() >= t) {
+            self.lookup = Some(resolve(self.name.clone()));
+        }
+        None
+    }
+
+    fn fail(&mut self, error: impl std::fmt::Display) {
+        if !self.failing {
+            log_message(format!("UDP send to {} failed: {}", self.name, error));
+            self.failing = true;
+        }
+    }
+}
+
+fn resolve(name: String) -> Receiver<Option<SocketAddr>> {
+    let (result, receiver) = sync_channel(1);
+    thread::spawn(move || {
+        let addr = name
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4));
+        // the output may have been replaced by a reload in the meantime
+        let _ = result.send(addr);
+    });
+    receiver
+}
+
+/// The `udptargets` of `[omsi2komsi]`, one socket for all of them.
+pub struct UdpOutput {
+    socket: Option<UdpSocket>,
+    targets: Vec<Target>,
+}
+
+impl UdpOutput {
+    pub fn new(targets: &[String]) -> UdpOutput {
+        // non-blocking, so a full network buffer never stalls the send loop
+        let socket = match UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+            socket.set_broadcast(true)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        }) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                log_message(format!("Could not create UDP socket: {}", e));
+                None
+            }
+        };
+        let mut targets: Vec<Target> = targets
+            .iter()
+            .map(|name| Target {
+                name: name.clone(),
+                addr: None,
+                lookup: None,
+                retry_at: None,
+                failing: false,
+            })
+            .collect();
+        // the lookups start now, so the first lines already reach the known hosts
+        for target in &mut targets {
+            target.addr();
+        }
+        UdpOutput { socket, targets }
+    }
+
+    pub fn send(&mut self, buf: &[u8]) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        if buf.is_empty() {
+            return;
+        }
+        for target in &mut self.targets {
+            let Some(addr) = target.addr() else {
+                continue;
+            };
+            match socket.send_to(buf, addr) {
+                Ok(_) => {
+                    if target.failing {
+                        log_message(format!("UDP target {} reachable again", target.name));
+                        target.failing = false;
+                    }
+                }
+                // the socket buffer is full, only this datagram is lost
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    target.fail(e);
+                    // resolved again, DHCP may have moved the host
+                    target.addr = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_once_resolved() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let target = receiver.local_addr().unwrap().to_string();
+        let mut output = UdpOutput::new(&[target]);
+
+        let start = Instant::now();
+        while output.targets[0].addr.is_none() && start.elapsed() < Duration::from_secs(2) {
+            output.send(b"y1\n");
+            thread::sleep(Duration::from_millis(10));
+        }
+        output.send(b"y2\n");
+        let mut buf = [0; 16];
+        let mut last = Vec::new();
+        while let Ok(len) = receiver.recv(&mut buf) {
+            last = buf[..len].to_vec();
+            if last == b"y2\n" {
+                break;
+            }
+        }
+        assert_eq!(last, b"y2\n");
+    }
+
+    #[test]
+    fn lookup_does_not_block() {
+        let mut output = UdpOutput::new(&["host.invalid:5005".to_string()]);
+        let start = Instant::now();
+        for _ in 0..10 {
+            output.send(b"y1\n");
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
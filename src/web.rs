@@ -0,0 +1,479 @@
+//! HTTP server for browser dashboards: static files from `webroot` and a WebSocket that streams the
+//! vehicle state and the `[stringvarlist]` values as JSON.
+//!
+//! Every message is one JSON object with a `timestamp` in milliseconds since 1970 and the fields that
+//! changed, `{"timestamp":…,"vehicle":{"speed":42},"strings":{"ibis_line":"38"}}`. A new client gets
+//! all fields first.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use komsi::vehicle::VehicleState;
+
+use crate::fields::{Fields, json_string};
+use crate::log_message;
+
+// messages waiting for a client, a client that falls further behind is disconnected
+const QUEUE_LEN: usize = 64;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+// time for a browser to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8192;
+// RFC 6455, appended to the key of the client for the handshake
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+struct Client {
+    peer: String,
+    queue: SyncSender<String>,
+}
+
+/// The `weblisten` server of `[omsi2komsi]`, dropping it stops listening and disconnects the clients.
+pub struct WebOutput {
+    listener: TcpListener,
+    root: PathBuf,
+    clients: Vec<Client>,
+    /// the fields of the last call, to find the changed ones
+    last: Fields,
+}
+
+impl WebOutput {
+    pub fn new(listen: &str, root: PathBuf) -> Option<WebOutput> {
+        // non-blocking, new clients are picked up by the send loop without waiting
+        match TcpListener::bind(listen).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        }) {
+            Ok(listener) => {
+                log_message(format!(
+                    "Web server listening on http://{}/ serving {}",
+                    listen,
+                    root.display()
+                ));
+                Some(WebOutput {
+                    listener,
+                    root,
+                    clients: Vec::new(),
+                    last: Fields::default(),
+                })
+            }
+            Err(e) => {
+                log_message(format!("Could not start web server on {}: {}", listen, e));
+                None
+            }
+        }
+    }
+
+    /// Sends the fields that changed since the last call, new clients get all fields.
+    pub fn send(&mut self, state: &VehicleState) {
+        let fields = Fields::read(state);
+        let changed = fields.changed_since(&self.last);
+        self.last = fields;
+
+        if !changed.is_empty() {
+            let message = message(&changed);
+            self.clients
+                .retain(|client| match client.queue.try_send(message.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        log_message(format!(
+                            "Web client {} cannot keep up, disconnecting",
+                            client.peer
+                        ));
+                        false
+                    }
+                    // the request was a file or the client has gone
+                    Err(TrySendError::Disconnected(_)) => false,
+                });
+        }
+
+        while let Ok((stream, addr)) = self.listener.accept() {
+            let peer = addr.to_string();
+            let (queue, receiver) = sync_channel(QUEUE_LEN);
+            // the first message cannot fail, the queue is still empty
+            let _ = queue.try_send(message(&self.last));
+            let thread_peer = peer.clone();
+            let root = self.root.clone();
+            thread::spawn(move || run(stream, thread_peer, root, receiver));
+            self.clients.push(Client { peer, queue });
+        }
+    }
+}
+
+fn message(fields: &Fields) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    let mut json = format!("{{\"timestamp\":{}", timestamp);
+    if !fields.vehicle.is_empty() {
+        json.push_str(",\"vehicle\":");
+        json_object(
+            &mut json,
+            fields
+                .vehicle
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.json())),
+        );
+    }
+    if !fields.strings.is_empty() {
+        json.push_str(",\"strings\":");
+        json_object(
+            &mut json,
+            fields
+                .strings
+                .iter()
+                .map(|(k, v)| (k.clone(), json_string(v))),
+        );
+    }
+    json.push('}');
+    json
+}
+
+/// Appends an object of names and JSON values.
+fn json_object(json: &mut String, fields: impl Iterator<Item = (String, String)>) {
+    json.push('{');
+    for (i, (name, value)) in fields.enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str(&json_string(&name));
+        json.push(':');
+        json.push_str(&value);
+    }
+    json.push('}');
+}
+
+/// Answers one connection: a file, an error or a WebSocket that gets the queued messages.
+fn run(mut stream: TcpStream, peer: String, root: PathBuf, queue: Receiver<String>) {
+    // accepted sockets inherit the non-blocking mode of the listener
+    if let Err(e) = stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(READ_TIMEOUT)))
+        .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+        .and_then(|_| stream.set_nodelay(true))
+    {
+        log_message(format!("Web client {}: {}", peer, e));
+        return;
+    }
+    let Some(request) = read_request(&mut stream) else {
+        return;
+    };
+
+    let mut lines = request.lines();
+    let mut parts = lines.next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let mut websocket_key = None;
+    let mut upgrade = false;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => websocket_key = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    if method != "GET" && method != "HEAD" {
+        let _ = respond(&mut stream, "405 Method Not Allowed", "text/plain", b"");
+        return;
+    }
+    match (upgrade, websocket_key) {
+        (true, Some(key)) => websocket(stream, peer, &key, queue),
+        _ => {
+            // the queue is only for WebSockets
+            drop(queue);
+            serve_file(&mut stream, &root, path, method == "HEAD");
+        }
+    }
+}
+
+/// Reads up to the empty line after the headers, `None` for a closed or broken connection.
+fn read_request(stream: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            return None;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    Some(String::from_utf8_lossy(&request).into_owned())
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body)
+}
+
+/// The file below `root` a request path names, `None` for paths that would leave it.
+fn file_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut file = root.to_path_buf();
+    // only plain names below the root, nothing like .. or C:, and \ is a separator on Windows
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(name) if !name.to_string_lossy().contains(['\\', ':']) => {
+                file.push(name)
+            }
+            _ => return None,
+        }
+    }
+    if path.ends_with('/') || file.is_dir() {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+fn serve_file(stream: &mut TcpStream, root: &Path, path: &str, head: bool) {
+    let Some(file) = file_path(root, path) else {
+        let _ = respond(stream, "404 Not Found", "text/plain", b"not found");
+        return;
+    };
+    let result = match std::fs::read(&file) {
+        Ok(body) => {
+            let body = if head { &[][..] } else { &body[..] };
+            respond(stream, "200 OK", content_type(&file), body)
+        }
+        Err(_) => respond(stream, "404 Not Found", "text/plain", b"not found"),
+    };
+    if let Err(e) = result {
+        log_message(format!(
+            "Web server could not send {}: {}",
+            file.display(),
+            e
+        ));
+    }
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Completes the handshake and writes the queued messages as text frames until the client is gone.
+fn websocket(mut stream: TcpStream, peer: String, key: &str, queue: Receiver<String>) {
+    let accept = accept_key(key);
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    if stream.write_all(handshake.as_bytes()).is_err() {
+        return;
+    }
+    log_message(format!("WebSocket client {} connected", peer));
+    while let Ok(message) = queue.recv() {
+        if let Err(e) = stream.write_all(&text_frame(message.as_bytes())) {
+            log_message(format!("WebSocket client {} disconnected: {}", peer, e));
+            return;
+        }
+    }
+    // a close frame, the client may still be there when the server stops
+    let _ = stream.write_all(&[0x88, 0]);
+    log_message(format!("WebSocket client {} disconnected", peer));
+}
+
+/// The `Sec-WebSocket-Accept` answer to a `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+/// A single unmasked text frame, servers never mask.
+fn text_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// SHA-1, only for the WebSocket handshake.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, v) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc6455_accept_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn sha1_known_answers() {
+        let hex = |d: [u8; 20]| d.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // two blocks
+        assert_eq!(
+            hex(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn frame_lengths() {
+        let frame = text_frame(&[b'x'; 125]);
+        assert_eq!(frame[..2], [0x81, 125]);
+        assert_eq!(frame.len(), 2 + 125);
+
+        let frame = text_frame(&[b'x'; 126]);
+        assert_eq!(frame[..4], [0x81, 126, 0, 126]);
+        assert_eq!(frame.len(), 4 + 126);
+
+        let frame = text_frame(&[b'x'; 65535]);
+        assert_eq!(frame[..4], [0x81, 126, 0xff, 0xff]);
+
+        let frame = text_frame(&[b'x'; 65536]);
+        assert_eq!(frame[..10], [0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame.len(), 10 + 65536);
+    }
+
+    #[test]
+    fn paths_stay_below_the_root() {
+        let root = Path::new("web");
+        assert_eq!(
+            file_path(root, "/app.js?v=2"),
+            Some(PathBuf::from("web/app.js"))
+        );
+        assert_eq!(file_path(root, "/"), Some(PathBuf::from("web/index.html")));
+        assert_eq!(
+            file_path(root, "/css/site.css"),
+            Some(PathBuf::from("web/css/site.css"))
+        );
+        assert_eq!(file_path(root, "/../omsi2komsi.opl"), None);
+        assert_eq!(file_path(root, "/css/../../secret"), None);
+        assert_eq!(file_path(root, "/C:/Windows/win.ini"), None);
+        assert_eq!(file_path(root, "/..\\omsi2komsi.opl"), None);
+        assert_eq!(
+            file_path(root, "//etc/passwd"),
+            Some(PathBuf::from("web/etc/passwd"))
+        );
+    }
+}